@@ -0,0 +1,520 @@
+//! Moving files into and out of graveyards.
+
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::{fs, io, thread};
+use walkdir::WalkDir;
+
+use crate::errors::*;
+use crate::graveyard::{annotations, describe};
+use crate::journal::{self, remove_any, Journal, SigintGuard};
+use crate::notice::{Notice, Question};
+use crate::progress::Progress;
+use crate::record::Grave;
+use crate::{checksum, humanize_bytes, mounts, shred};
+
+pub const BIG_FILE_THRESHOLD: u64 = 500000000; // 500 MB
+pub const LOW_SPACE_PERCENT: u64 = 10;
+pub const SHRED_PASSES: u32 = 3;
+
+/// What to do with files over the big file threshold that need to be copied
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BigFiles {
+    Prompt,
+    Bury,
+    Delete,
+}
+
+impl BigFiles {
+    pub fn parse(s: &str) -> Option<BigFiles> {
+        match s {
+            "prompt" => Some(BigFiles::Prompt),
+            "bury" => Some(BigFiles::Bury),
+            "delete" => Some(BigFiles::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BigFiles::Prompt => "prompt",
+            BigFiles::Bury => "bury",
+            BigFiles::Delete => "delete",
+        }
+    }
+}
+
+/// A file that was unlinked rather than buried, and why
+pub struct Tombstone {
+    pub path: PathBuf,
+    pub size: u64,
+    pub reason: &'static str,
+}
+
+/// Outcome of copying a single file
+enum Copied {
+    Copied,
+    Destroyed(&'static str),
+}
+
+/// Settings that affect how files are moved into and out of the graveyard
+#[derive(Clone, Copy)]
+pub struct BuryOptions<'a> {
+    /// Verify copied files and store grave checksums in the record
+    pub checksum: bool,
+    /// Show progress on stderr while copying across filesystems
+    pub progress: bool,
+    /// Number of files to copy at once when copying a directory
+    pub jobs: usize,
+    /// Unlink targets instead of burying them
+    pub permanent: bool,
+    /// Overwrite files this many times before unlinking them
+    pub shred: Option<u32>,
+    /// Passes to use when a policy asks for shredding without `shred`
+    pub shred_passes: u32,
+    /// Paths to refuse to remove, in addition to the built-in ones
+    pub protected: &'a [PathBuf],
+    /// Allow removing protected paths
+    pub no_preserve_root: bool,
+    /// Leave mount points inside buried directories where they are
+    pub one_file_system: bool,
+    /// Bury files on other filesystems in a graveyard on that filesystem
+    pub per_filesystem_graveyards: bool,
+    /// Files bigger than this many bytes are subject to `big_files`
+    pub big_file_threshold: u64,
+    pub big_files: BigFiles,
+    /// Ask before a copy leaves less than this percentage of a filesystem free
+    pub low_space_percent: u64,
+    /// Report which policy applies to each target
+    pub verbose: bool,
+    /// Only do what unburying can undo: bury big and special files rather
+    /// than destroy them, and refuse to unlink targets outright, whether
+    /// `permanent`, a policy or the target being in the graveyard asks to
+    pub reversible: bool,
+    /// Why targets are being removed, to note in the record
+    pub message: Option<&'a str>,
+    /// Tags to note in the record
+    pub tags: &'a [String],
+    /// Answers questions that come up along the way; without it, the
+    /// answer is always no
+    pub ask: Option<&'a Ask>,
+    /// Told about anything worth reporting along the way
+    pub notify: Option<&'a Notify>,
+}
+
+/// Decides a question, from any of the copy threads
+pub type Ask = dyn Fn(&Question) -> bool + Sync;
+/// Hears about a notice, from any of the copy threads
+pub type Notify = dyn Fn(&Notice) + Sync;
+
+impl Default for BuryOptions<'_> {
+    fn default() -> Self {
+        BuryOptions {
+            checksum: false,
+            progress: false,
+            jobs: 1,
+            permanent: false,
+            shred: None,
+            shred_passes: SHRED_PASSES,
+            protected: &[],
+            no_preserve_root: false,
+            one_file_system: false,
+            per_filesystem_graveyards: true,
+            big_file_threshold: BIG_FILE_THRESHOLD,
+            big_files: BigFiles::Bury,
+            low_space_percent: LOW_SPACE_PERCENT,
+            verbose: false,
+            reversible: false,
+            message: None,
+            tags: &[],
+            ask: None,
+            notify: None,
+        }
+    }
+}
+
+impl BuryOptions<'_> {
+    /// Put a question to `ask`, taking no for an answer if there's no one
+    pub(crate) fn confirm(&self, question: Question) -> bool {
+        self.ask.is_some_and(|ask| ask(&question))
+    }
+
+    /// Pass a notice on to `notify`, if anyone's listening
+    pub(crate) fn tell(&self, notice: Notice) {
+        if let Some(notify) = self.notify {
+            notify(&notice);
+        }
+    }
+}
+
+/// Unlink a file or directory tree, overwriting it first if `opts.shred`
+/// says to
+pub fn unlink<P: AsRef<Path>>(path: P, opts: &BuryOptions) -> io::Result<()> {
+    match opts.shred {
+        Some(passes) => shred::shred(path, passes, opts),
+        None => remove_any(path),
+    }
+}
+
+/// Move source to dest, returning any files that were destroyed on the way
+/// rather than copied
+pub fn bury<S, D>(
+    source: S,
+    dest: D,
+    opts: &BuryOptions,
+    journal: &Journal,
+) -> Result<Vec<Tombstone>>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let parent = dest.parent().ok_or("Couldn't get parent of dest")?;
+    fs::create_dir_all(parent).chain_err(|| "Couldn't create parent dir")?;
+
+    // Try a simple rename, which will only work within the same mount point.
+    // Trying to rename across filesystems will throw errno 18.
+    if fs::rename(source, dest).is_ok() {
+        return Ok(Vec::new());
+    }
+
+    // If that didn't work, then copy and rm.  Catch SIGINT while copying so
+    // that an interrupted copy is rolled back rather than left half done.
+    let _sigint = SigintGuard::install();
+    let (bytes, files) = tree_size(source);
+    let cross_device = || ErrorKind::CrossDevice(source.to_owned(), dest.to_owned());
+    check_free_space(source, bytes, parent, opts).chain_err(cross_device)?;
+    // What the record should say if this is a burial that the next run
+    // ends up finishing
+    let fields = fs::symlink_metadata(source)
+        .map(|metadata| {
+            let mut fields = describe(&metadata, bytes);
+            fields.extend(annotations(opts));
+            Grave::new(source, dest, &fields).fields
+        })
+        .unwrap_or_default();
+    journal
+        .begin(source, dest, &fields)
+        .chain_err(|| "Couldn't write to the journal")?;
+
+    let progress = &if opts.progress {
+        Progress::new(bytes, files)
+    } else {
+        Progress::hidden()
+    };
+    let tombstones = match copy_tree(source, dest, opts, progress) {
+        Ok(tombstones) => tombstones,
+        Err(e) => {
+            // Clean up any partial buries due to permission error or interrupt
+            remove_any(dest).ok();
+            journal.finish(dest).ok();
+            return Err(e).chain_err(cross_device);
+        }
+    };
+
+    // Once the copy is complete, removing the source goes ahead even if we
+    // get interrupted; if we're killed, the next run will finish the job.
+    journal
+        .removing(source, dest, &fields)
+        .chain_err(|| "Couldn't write to the journal")?;
+    if let Some(passes) = opts.shred {
+        for t in &tombstones {
+            shred::shred(&t.path, passes, opts)
+                .chain_err(|| format!("Failed to shred {}", t.path.display()))?;
+        }
+    }
+    remove_any(source).chain_err(|| format!("Failed to remove {}", source.display()))?;
+
+    Ok(tombstones)
+}
+
+/// Make sure that copying bytes worth of source into dir will fit, refusing
+/// if it won't and asking first if it would leave less than `low_space_percent`
+/// of the filesystem free.
+fn check_free_space(source: &Path, bytes: u64, dir: &Path, opts: &BuryOptions) -> Result<()> {
+    let (available, total) = match mounts::space(dir) {
+        Ok(space) => space,
+        // If we can't tell, just try it
+        Err(_) => return Ok(()),
+    };
+    let fs = mounts::mount_root(dir).unwrap_or_else(|| dir.to_owned());
+    if bytes > available {
+        bail!(
+            "Not enough space to copy {} ({}) to {}: only {} free",
+            source.display(),
+            humanize_bytes(bytes),
+            fs.display(),
+            humanize_bytes(available)
+        );
+    }
+    if available - bytes < total / 100 * opts.low_space_percent
+        && !opts.confirm(Question::LowSpace {
+            source,
+            bytes,
+            left: available - bytes,
+            fs: &fs,
+        })
+    {
+        bail!(ErrorKind::Declined(format!(
+            "Not copying {}",
+            source.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Copy a file or directory tree from source to dest, stopping early if
+/// SIGINT is received.
+fn copy_tree(
+    source: &Path,
+    dest: &Path,
+    opts: &BuryOptions,
+    progress: &Progress,
+) -> Result<Vec<Tombstone>> {
+    let metadata = fs::symlink_metadata(source).chain_err(|| "Couldn't get metadata")?;
+    let tombstones = if metadata.is_dir() {
+        copy_dir(source, dest, opts, progress)?
+    } else {
+        match copy_file(source, dest, opts, progress) {
+            Ok(Copied::Copied) => Vec::new(),
+            Ok(Copied::Destroyed(reason)) => vec![Tombstone {
+                path: source.to_owned(),
+                size: metadata.len(),
+                reason,
+            }],
+            Err(e) => {
+                return Err(e).chain_err(|| {
+                    format!(
+                        "Failed to copy file from {} to {}",
+                        source.display(),
+                        dest.display()
+                    )
+                })
+            }
+        }
+    };
+    if journal::interrupted() {
+        bail!(ErrorKind::Interrupted);
+    }
+
+    Ok(tombstones)
+}
+
+/// Walk a directory, creating directories in order and handing files off to
+/// a pool of `opts.jobs` workers to copy.  Every file is dispatched after
+/// the ones before it in the walk, so if several copies fail we can always
+/// report the first one.
+fn copy_dir(
+    source: &Path,
+    dest: &Path,
+    opts: &BuryOptions,
+    progress: &Progress,
+) -> Result<Vec<Tombstone>> {
+    let (tx, rx) = mpsc::sync_channel::<(usize, PathBuf, PathBuf)>(opts.jobs * 2);
+    let rx = Mutex::new(rx);
+    // Failures along with their position in the walk
+    let errors: Mutex<Vec<(usize, Error)>> = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+    let tombstones: Mutex<Vec<(usize, Tombstone)>> = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..opts.jobs.max(1) {
+            scope.spawn(|| loop {
+                // Hold the lock only long enough to take the next job
+                let job = rx.lock().unwrap().recv();
+                let (i, from, to) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if failed.load(Ordering::SeqCst) || journal::interrupted() {
+                    continue;
+                }
+                match copy_file(&from, &to, opts, progress).chain_err(|| {
+                    format!(
+                        "Failed to copy file from {} to {}",
+                        from.display(),
+                        to.display()
+                    )
+                }) {
+                    Ok(Copied::Copied) => progress.file_done(),
+                    Ok(Copied::Destroyed(reason)) => {
+                        let size = fs::symlink_metadata(&from).map(|m| m.len()).unwrap_or(0);
+                        tombstones.lock().unwrap().push((
+                            i,
+                            Tombstone {
+                                path: from,
+                                size,
+                                reason,
+                            },
+                        ));
+                    }
+                    Err(e) => {
+                        failed.store(true, Ordering::SeqCst);
+                        errors.lock().unwrap().push((i, e));
+                    }
+                }
+            });
+        }
+
+        // Walk the source, creating directories and queueing files as needed
+        for (i, entry) in WalkDir::new(source)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .enumerate()
+        {
+            if failed.load(Ordering::SeqCst) || journal::interrupted() {
+                break;
+            }
+            // Path without the top-level directory
+            let orphan: &Path = match entry.path().strip_prefix(source) {
+                Ok(orphan) => orphan,
+                Err(_) => continue,
+            };
+            if entry.file_type().is_dir() {
+                let dir = dest.join(orphan);
+                if let Err(e) = fs::create_dir_all(&dir)
+                    .and_then(|_| keep_owner(entry.path(), &dir))
+                    .chain_err(|| {
+                        format!(
+                            "Failed to create {} in {}",
+                            entry.path().display(),
+                            dir.display()
+                        )
+                    })
+                {
+                    failed.store(true, Ordering::SeqCst);
+                    errors.lock().unwrap().push((i, e));
+                }
+            } else if tx
+                .send((i, entry.path().to_owned(), dest.join(orphan)))
+                .is_err()
+            {
+                break;
+            }
+        }
+        drop(tx);
+    });
+
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by_key(|(i, _)| *i);
+    let others = errors.len().saturating_sub(1);
+    if let Some((_, e)) = errors.into_iter().next() {
+        if others > 0 {
+            return Err(e)
+                .chain_err(|| format!("Copy failed ({} more file(s) also failed)", others));
+        }
+        return Err(e);
+    }
+
+    let mut tombstones = tombstones.into_inner().unwrap();
+    tombstones.sort_by_key(|(i, _)| *i);
+    Ok(tombstones.into_iter().map(|(_, t)| t).collect())
+}
+
+fn copy_file<S, D>(
+    source: S,
+    dest: D,
+    opts: &BuryOptions,
+    progress: &Progress,
+) -> io::Result<Copied>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let metadata = fs::symlink_metadata(source)?;
+    let filetype = metadata.file_type();
+
+    if metadata.len() > opts.big_file_threshold && !opts.reversible {
+        let delete = match opts.big_files {
+            BigFiles::Bury => false,
+            BigFiles::Delete => true,
+            BigFiles::Prompt => {
+                progress.clear();
+                opts.confirm(Question::DeleteBigFile(source, metadata.len()))
+            }
+        };
+        if delete {
+            return Ok(Copied::Destroyed("big-file"));
+        }
+    }
+
+    if filetype.is_file() {
+        if progress.enabled() {
+            copy_with_progress(source, dest, progress)?;
+        } else {
+            fs::copy(source, dest)?;
+        }
+        keep_owner(source, dest)?;
+        if opts.checksum {
+            checksum::verify_copy(source, dest)?;
+        }
+    } else if filetype.is_fifo() {
+        let mode = metadata.permissions().mode();
+        std::process::Command::new("mkfifo")
+            .arg(dest)
+            .arg("-m")
+            .arg(mode.to_string());
+    } else if filetype.is_symlink() {
+        let target = fs::read_link(source)?;
+        std::os::unix::fs::symlink(target, dest)?;
+        keep_owner(source, dest)?;
+    } else if let Err(e) = fs::copy(source, dest) {
+        // Special file: Try copying it as normal, but this probably won't work
+        progress.clear();
+        if opts.reversible || !opts.confirm(Question::DeleteSpecialFile(source)) {
+            return Err(e);
+        }
+        return Ok(Copied::Destroyed("special-file"));
+    }
+
+    Ok(Copied::Copied)
+}
+
+/// When running as root, give a copy the same owner as the original, the
+/// way a rename would
+fn keep_owner(source: &Path, dest: &Path) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+    let metadata = fs::symlink_metadata(source)?;
+    std::os::unix::fs::lchown(dest, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+/// Copy a regular file in chunks, reporting progress as we go.  Unlike
+/// `fs::copy`, this can be interrupted partway through a big file.
+fn copy_with_progress(source: &Path, dest: &Path, progress: &Progress) -> io::Result<()> {
+    let mut reader = fs::File::open(source)?;
+    let mut writer = fs::File::create(dest)?;
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        progress.add_bytes(n as u64);
+        if journal::interrupted() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "Interrupted"));
+        }
+    }
+    fs::set_permissions(dest, reader.metadata()?.permissions())
+}
+
+/// Return the total size in bytes and the number of files (anything but
+/// directories) in a file or directory tree
+pub fn tree_size<P: AsRef<Path>>(path: P) -> (u64, u64) {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| !x.file_type().is_dir())
+        .filter_map(|x| x.metadata().ok())
+        .fold((0, 0), |(bytes, files), m| (bytes + m.len(), files + 1))
+}
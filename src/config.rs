@@ -0,0 +1,458 @@
+//! Settings merged from the command line, the environment, the config file
+//! and built-in defaults, in that order of precedence.
+
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs, io};
+
+use crate::errors::*;
+use rm_improved::{get_user, sudo_user, BigFiles, Owner, StoreKind};
+use rm_improved::{BIG_FILE_THRESHOLD, LOW_SPACE_PERCENT, SHRED_PASSES};
+
+const GRAVEYARD: &str = "/tmp/graveyard";
+const LINES_TO_INSPECT: usize = 6;
+const FILES_TO_INSPECT: usize = 6;
+
+/// How to print listings such as --seance
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Plain,
+    /// One JSON object per line
+    Json,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "plain" => Some(Format::Plain),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// Whose graveyard to use when running under sudo
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SudoGraveyard {
+    /// The graveyard of the user who ran sudo
+    User,
+    /// Root's own graveyard
+    Root,
+}
+
+impl SudoGraveyard {
+    fn as_str(self) -> &'static str {
+        match self {
+            SudoGraveyard::User => "user",
+            SudoGraveyard::Root => "root",
+        }
+    }
+}
+
+/// The contents of config.toml, where every key is optional
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    graveyard: Option<String>,
+    per_filesystem_graveyards: Option<bool>,
+    big_file_threshold: Option<u64>,
+    big_files: Option<BigFiles>,
+    low_space_percent: Option<u64>,
+    lines_to_inspect: Option<usize>,
+    files_to_inspect: Option<usize>,
+    jobs: Option<usize>,
+    shred_passes: Option<u32>,
+    protect: Option<Vec<String>>,
+    retention_days: Option<u64>,
+    format: Option<Format>,
+    sudo_graveyard: Option<SudoGraveyard>,
+    record_store: Option<StoreKind>,
+}
+
+/// Where a setting came from
+#[derive(Clone)]
+pub enum Source {
+    Default,
+    Flag(&'static str),
+    Env(&'static str),
+    File(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::Flag(name) => write!(f, "--{}", name),
+            Source::Env(name) => write!(f, "${}", name),
+            Source::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// The effective settings for this run
+pub struct Settings {
+    /// Config file that was read, if there was one
+    pub file: Option<PathBuf>,
+    pub graveyard: Setting<PathBuf>,
+    /// Every graveyard we know of: the ones the flag, the environment and
+    /// the config file name, and the defaults, whether or not they exist
+    pub known_graveyards: Vec<Setting<PathBuf>>,
+    /// Bury files on other filesystems under `<mount root>/.graveyard-$UID`
+    pub per_filesystem_graveyards: Setting<bool>,
+    pub big_file_threshold: Setting<u64>,
+    pub big_files: Setting<BigFiles>,
+    /// Ask before a copy leaves less than this percentage of a filesystem free
+    pub low_space_percent: Setting<u64>,
+    pub lines_to_inspect: Setting<usize>,
+    pub files_to_inspect: Setting<usize>,
+    pub jobs: Setting<usize>,
+    pub shred_passes: Setting<u32>,
+    /// Paths to refuse to remove, in addition to the built-in ones
+    pub protect: Vec<Setting<PathBuf>>,
+    /// Decompose graves older than this many days; 0 keeps them forever
+    pub retention_days: Setting<u64>,
+    pub format: Setting<Format>,
+    pub sudo_graveyard: Setting<SudoGraveyard>,
+    /// How new graveyards keep their record
+    pub record_store: Setting<StoreKind>,
+    /// Who the graveyard belongs to: us, or whoever ran sudo
+    pub owner: Owner,
+}
+
+impl Settings {
+    /// Work out the settings for this run.  Relative paths are taken
+    /// relative to cwd, and a leading `~/` in the config file means $HOME.
+    pub fn load(matches: &ArgMatches, cwd: &Path) -> Result<Settings> {
+        let (file, config) = read_config(matches.value_of("config"))?;
+
+        let sudo_graveyard = first(
+            vec![config.sudo_graveyard.and_then(|v| from_file(&file, v))],
+            SudoGraveyard::User,
+        );
+        let (owner, user) = match sudo_user() {
+            Some(sudo) if sudo_graveyard.value == SudoGraveyard::User => sudo,
+            _ => (Owner::current(), get_user()),
+        };
+
+        let candidates = vec![
+            matches
+                .value_of("graveyard")
+                .map(|g| flag("graveyard", g.into())),
+            env::var("GRAVEYARD").ok().map(|g| Setting {
+                value: g.into(),
+                source: Source::Env("GRAVEYARD"),
+            }),
+            config
+                .graveyard
+                .and_then(|g| from_file(&file, expand_home(&g))),
+            env::var("XDG_DATA_HOME").ok().map(|d| Setting {
+                value: Path::new(&d).join("graveyard"),
+                source: Source::Env("XDG_DATA_HOME"),
+            }),
+        ];
+        let default = PathBuf::from(format!("{}-{}", GRAVEYARD, user));
+        let mut known_graveyards: Vec<Setting<PathBuf>> =
+            candidates.iter().flatten().cloned().collect();
+        known_graveyards.push(Setting {
+            value: default.clone(),
+            source: Source::Default,
+        });
+        // Where $XDG_DATA_HOME would put it if it were set
+        if env::var_os("XDG_DATA_HOME").is_none() {
+            if let Some(home) = env::var_os("HOME") {
+                known_graveyards.push(Setting {
+                    value: Path::new(&home).join(".local/share/graveyard"),
+                    source: Source::Default,
+                });
+            }
+        }
+        let graveyard = first(candidates, default);
+        // Graves are looked up by absolute path, so the graveyard has to be
+        // one too
+        let graveyard = Setting {
+            value: cwd.join(graveyard.value),
+            ..graveyard
+        };
+        let mut seen: Vec<PathBuf> = Vec::new();
+        let known_graveyards = known_graveyards
+            .into_iter()
+            .map(|g| Setting {
+                value: cwd.join(g.value),
+                ..g
+            })
+            .filter(|g| {
+                let new = !seen.contains(&g.value);
+                seen.push(g.value.clone());
+                new
+            })
+            .collect();
+
+        let big_files = first(
+            vec![
+                if matches.occurrences_of("big-files") > 0 {
+                    matches
+                        .value_of("big-files")
+                        .and_then(BigFiles::parse)
+                        .map(|b| flag("big-files", b))
+                } else {
+                    None
+                },
+                config.big_files.and_then(|v| from_file(&file, v)),
+            ],
+            BigFiles::Prompt,
+        );
+
+        let jobs = first(
+            vec![
+                match matches.value_of("jobs") {
+                    Some(n) => Some(flag("jobs", parse_positive(n, "--jobs")?)),
+                    None => None,
+                },
+                match config.jobs {
+                    Some(0) => bail!("jobs must be a positive number"),
+                    jobs => jobs.and_then(|v| from_file(&file, v)),
+                },
+            ],
+            1,
+        );
+
+        let shred_passes = first(
+            vec![
+                match matches.value_of("shred-passes") {
+                    Some(n) => match n.parse() {
+                        Ok(n) => Some(flag("shred-passes", n)),
+                        Err(_) => bail!("--shred-passes must be a number, not {}", n),
+                    },
+                    None => None,
+                },
+                config.shred_passes.and_then(|v| from_file(&file, v)),
+            ],
+            SHRED_PASSES,
+        );
+
+        let low_space_percent = first(
+            vec![match config.low_space_percent {
+                Some(p) if p > 100 => bail!("low_space_percent must be at most 100"),
+                p => p.and_then(|v| from_file(&file, v)),
+            }],
+            LOW_SPACE_PERCENT,
+        );
+
+        let format = first(
+            vec![
+                matches
+                    .value_of("format")
+                    .and_then(Format::parse)
+                    .map(|f| flag("format", f)),
+                config.format.and_then(|v| from_file(&file, v)),
+            ],
+            Format::Plain,
+        );
+
+        let protect = matches
+            .values_of("protect")
+            .into_iter()
+            .flatten()
+            .map(|p| flag("protect", PathBuf::from(p)))
+            .chain(
+                env::var("RIP_PROTECT")
+                    .unwrap_or_default()
+                    .split(':')
+                    .filter(|p| !p.is_empty())
+                    .map(|p| Setting {
+                        value: PathBuf::from(p),
+                        source: Source::Env("RIP_PROTECT"),
+                    }),
+            )
+            .chain(
+                config
+                    .protect
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|p| from_file(&file, expand_home(p))),
+            )
+            .map(|p| Setting {
+                value: cwd.join(p.value),
+                ..p
+            })
+            .collect();
+
+        Ok(Settings {
+            graveyard,
+            known_graveyards,
+            per_filesystem_graveyards: first(
+                vec![config
+                    .per_filesystem_graveyards
+                    .and_then(|v| from_file(&file, v))],
+                true,
+            ),
+            big_file_threshold: first(
+                vec![config.big_file_threshold.and_then(|v| from_file(&file, v))],
+                BIG_FILE_THRESHOLD,
+            ),
+            big_files,
+            low_space_percent,
+            lines_to_inspect: first(
+                vec![config.lines_to_inspect.and_then(|v| from_file(&file, v))],
+                LINES_TO_INSPECT,
+            ),
+            files_to_inspect: first(
+                vec![config.files_to_inspect.and_then(|v| from_file(&file, v))],
+                FILES_TO_INSPECT,
+            ),
+            jobs,
+            shred_passes,
+            protect,
+            retention_days: first(
+                vec![config.retention_days.and_then(|v| from_file(&file, v))],
+                0,
+            ),
+            format,
+            sudo_graveyard,
+            record_store: first(
+                vec![config.record_store.and_then(|v| from_file(&file, v))],
+                StoreKind::Text,
+            ),
+            owner,
+            file,
+        })
+    }
+
+    /// Print the settings as TOML, noting where each one came from
+    pub fn print(&self) {
+        match self.file {
+            Some(ref path) => println!("# Read from {}", path.display()),
+            None => println!("# No config file found"),
+        }
+        print_setting("graveyard", &self.graveyard, |g| quote(g));
+        print_setting(
+            "per_filesystem_graveyards",
+            &self.per_filesystem_graveyards,
+            |b| b.to_string(),
+        );
+        print_setting("big_file_threshold", &self.big_file_threshold, |n| {
+            n.to_string()
+        });
+        print_setting("big_files", &self.big_files, |b| quote(b.as_str()));
+        print_setting("low_space_percent", &self.low_space_percent, |n| {
+            n.to_string()
+        });
+        print_setting("lines_to_inspect", &self.lines_to_inspect, |n| {
+            n.to_string()
+        });
+        print_setting("files_to_inspect", &self.files_to_inspect, |n| {
+            n.to_string()
+        });
+        print_setting("jobs", &self.jobs, |n| n.to_string());
+        print_setting("shred_passes", &self.shred_passes, |n| n.to_string());
+        if self.protect.is_empty() {
+            println!("{:<40} # default", "protect = []");
+        } else {
+            println!("protect = [");
+            for p in &self.protect {
+                println!("{:<40} # {}", format!("    {},", quote(&p.value)), p.source);
+            }
+            println!("]");
+        }
+        print_setting("retention_days", &self.retention_days, |n| n.to_string());
+        print_setting("format", &self.format, |f| quote(f.as_str()));
+        print_setting("sudo_graveyard", &self.sudo_graveyard, |s| {
+            quote(s.as_str())
+        });
+        print_setting("record_store", &self.record_store, |s| quote(s.as_str()));
+    }
+}
+
+/// Read the config file named by --config, or else the one in the XDG config
+/// directory if it exists
+fn read_config(flag: Option<&str>) -> Result<(Option<PathBuf>, ConfigFile)> {
+    let path = match flag {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+        {
+            Some(dir) => dir.join("rip").join("config.toml"),
+            None => return Ok((None, ConfigFile::default())),
+        },
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && flag.is_none() => {
+            return Ok((None, ConfigFile::default()))
+        }
+        Err(e) => {
+            return Err(e).chain_err(|| format!("Couldn't read config file {}", path.display()))
+        }
+    };
+    let config = toml::from_str(&contents)
+        .chain_err(|| format!("Invalid config file {}", path.display()))?;
+    Ok((Some(path), config))
+}
+
+/// The first setting that was given, or else the default
+fn first<T>(candidates: Vec<Option<Setting<T>>>, default: T) -> Setting<T> {
+    candidates.into_iter().flatten().next().unwrap_or(Setting {
+        value: default,
+        source: Source::Default,
+    })
+}
+
+fn from_file<T>(file: &Option<PathBuf>, value: T) -> Option<Setting<T>> {
+    file.clone().map(|path| Setting {
+        value,
+        source: Source::File(path),
+    })
+}
+
+fn flag<T>(name: &'static str, value: T) -> Setting<T> {
+    Setting {
+        value,
+        source: Source::Flag(name),
+    }
+}
+
+fn parse_positive(n: &str, name: &str) -> Result<usize> {
+    match n.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{} must be a positive number, not {}", name, n),
+    }
+}
+
+/// Replace a leading `~` with $HOME
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), env::var_os("HOME")) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            PathBuf::from(format!("{}{}", Path::new(&home).display(), rest))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+fn quote<P: AsRef<Path>>(s: P) -> String {
+    format!("{:?}", s.as_ref().display().to_string())
+}
+
+fn print_setting<T, F: Fn(&T) -> String>(key: &str, setting: &Setting<T>, show: F) {
+    println!(
+        "{:<40} # {}",
+        format!("{} = {}", key, show(&setting.value)),
+        setting.source
+    );
+}
@@ -0,0 +1,131 @@
+//! Errors, and the exit status `rip` reports for each kind.
+//!
+//! | Status | Kind                  | Meaning                                    |
+//! |--------|-----------------------|--------------------------------------------|
+//! | 0      |                       | Success                                    |
+//! | 1      | `Msg` and the rest    | Any other error, including bad arguments   |
+//! | 2      | `NotFound`            | A target doesn't exist                     |
+//! | 3      | `PermissionDenied`    | Not allowed to move or remove something    |
+//! | 4      | `Protected`           | Refused to remove a protected path         |
+//! | 5      | `Declined`            | The user said no when asked to go ahead    |
+//! | 6      | `CrossDevice`         | Copying to another filesystem failed       |
+//! | 7      | `RecordCorrupt`,      | The record has a malformed line, or        |
+//! |        | `RecordInconsistent`  | `--fsck` found problems with it            |
+//! | 8      | `UntrustedGraveyard`, | Refused to use a graveyard or a `.riprc`   |
+//! |        | `UntrustedPolicy`     | that someone else could have planted       |
+//! | 9      | `Damaged`             | Graves failed `--verify`                   |
+//! | 130    | `Interrupted`         | Stopped by Ctrl-C                          |
+//!
+//! An error wrapping others reports the status of the innermost one that has
+//! its own, so a copy to another filesystem that fails because a file can't
+//! be read is a permission error rather than a cross-device one.
+
+use std::error::Error as StdError;
+use std::io;
+use std::path::PathBuf;
+
+error_chain! {
+    errors {
+        NotFound(path: PathBuf) {
+            description("no such file or directory")
+            display("Cannot remove {}: no such file or directory", path.display())
+        }
+        PermissionDenied(path: PathBuf) {
+            description("permission denied")
+            display("Cannot remove {}: permission denied", path.display())
+        }
+        Protected(path: PathBuf, reason: String) {
+            description("protected path")
+            display(
+                "Refusing to remove {}: {} (pass --no-preserve-root to override)",
+                path.display(),
+                reason
+            )
+        }
+        Declined(what: String) {
+            description("declined")
+            display("{}: declined", what)
+        }
+        CrossDevice(source: PathBuf, dest: PathBuf) {
+            description("copy across filesystems failed")
+            display("Couldn't copy {} to {}", source.display(), dest.display())
+        }
+        RecordCorrupt(path: PathBuf, line: usize) {
+            description("record is corrupt")
+            display(
+                "Record {} is corrupt at line {} (rip --fsck --repair drops malformed lines)",
+                path.display(),
+                line
+            )
+        }
+        RecordInconsistent(problems: usize) {
+            description("record doesn't match the graveyard")
+            display(
+                "Found {} problem(s) with the record (rip --fsck --repair fixes them)",
+                problems
+            )
+        }
+        UntrustedGraveyard(path: PathBuf, problem: String) {
+            description("untrusted graveyard")
+            display("Refusing to use graveyard {}: {}", path.display(), problem)
+        }
+        UntrustedPolicy(path: PathBuf, problem: String) {
+            description("untrusted policy")
+            display("Refusing to use policy {}: {}", path.display(), problem)
+        }
+        Damaged(count: usize) {
+            description("graves failed verification")
+            display("{} grave(s) failed verification", count)
+        }
+        Interrupted {
+            description("interrupted")
+            display("Interrupted")
+        }
+        /// Several targets failed; status is the exit status of the first
+        PartlyFailed(failed: usize, total: usize, succeeded: usize, status: i32) {
+            description("some targets failed")
+            display("Failed to remove {} of {} targets ({} succeeded)", failed, total, succeeded)
+        }
+    }
+}
+
+impl ErrorKind {
+    /// The exit status for this kind of error, if it has its own
+    fn status(&self) -> Option<i32> {
+        match *self {
+            ErrorKind::NotFound(_) => Some(2),
+            ErrorKind::PermissionDenied(_) => Some(3),
+            ErrorKind::Protected(..) => Some(4),
+            ErrorKind::Declined(_) => Some(5),
+            ErrorKind::CrossDevice(..) => Some(6),
+            ErrorKind::RecordCorrupt(..) | ErrorKind::RecordInconsistent(_) => Some(7),
+            ErrorKind::UntrustedGraveyard(..) | ErrorKind::UntrustedPolicy(..) => Some(8),
+            ErrorKind::Damaged(_) => Some(9),
+            ErrorKind::Interrupted => Some(130),
+            ErrorKind::PartlyFailed(.., status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// The status `rip` exits with for this error; see the table above
+    pub fn exit_code(&self) -> i32 {
+        let mut status = 1;
+        let mut next: Option<&(dyn StdError + 'static)> = Some(self);
+        while let Some(e) = next {
+            if let Some(e) = e.downcast_ref::<Error>() {
+                status = e.kind().status().unwrap_or(status);
+            } else if let Some(e) = e.downcast_ref::<io::Error>() {
+                match e.kind() {
+                    io::ErrorKind::PermissionDenied => status = 3,
+                    // Copies stop this way when SIGINT is caught
+                    io::ErrorKind::Interrupted => status = 130,
+                    _ => {}
+                }
+            }
+            next = e.source();
+        }
+        status
+    }
+}
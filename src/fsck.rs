@@ -0,0 +1,156 @@
+//! Checking the record against what's actually in the graveyards.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::errors::*;
+use crate::graveyard::{Graveyard, GRAVEYARDS};
+use crate::history::HISTORY;
+use crate::journal::JOURNAL;
+use crate::record::{Grave, Malformed, RECORD};
+use crate::symlink_exists;
+
+/// Problems found by `Graveyard::fsck`
+#[derive(Default)]
+pub struct Fsck {
+    /// Well-formed entries in the record
+    pub entries: usize,
+    /// Entries for graves that are no longer in the graveyard
+    pub dangling: Vec<Grave>,
+    /// Files in a graveyard that the record doesn't mention
+    pub orphans: Vec<PathBuf>,
+    pub malformed: Malformed,
+    /// Earlier entries for a grave that's recorded more than once
+    pub duplicates: Vec<Grave>,
+}
+
+impl Fsck {
+    pub fn problems(&self) -> usize {
+        self.dangling.len() + self.orphans.len() + self.malformed.len() + self.duplicates.len()
+    }
+}
+
+impl Graveyard {
+    /// Compare the record with the graveyards.  If repair is set, drop
+    /// dangling, malformed and duplicate entries, and record orphans as if
+    /// they had been buried from where their path in the graveyard says.
+    /// Orphans are only looked for in graveyards this one doesn't share,
+    /// since in the others they may well be another graveyard's graves.
+    pub fn fsck(&self, repair: bool) -> Result<Fsck> {
+        let (graves, malformed) = self.record().scan()?;
+        let mut report = Fsck {
+            entries: graves.len(),
+            malformed,
+            ..Fsck::default()
+        };
+
+        // Only the last entry for a grave counts
+        let mut last: HashMap<&Path, usize> = HashMap::new();
+        for (i, grave) in graves.iter().enumerate() {
+            if !grave.is_tombstone() {
+                last.insert(&grave.dest, i);
+            }
+        }
+        let mut keep: Vec<Grave> = Vec::new();
+        for (i, grave) in graves.iter().enumerate() {
+            if grave.is_tombstone() {
+                keep.push(grave.clone());
+            } else if last[grave.dest.as_path()] != i {
+                report.duplicates.push(grave.clone());
+            } else if !symlink_exists(&grave.dest) {
+                report.dangling.push(grave.clone());
+            } else {
+                keep.push(grave.clone());
+            }
+        }
+
+        let graveyards = self.all();
+        let dests: HashSet<&Path> = last.keys().copied().collect();
+        let mut ancestors: HashSet<&Path> = HashSet::new();
+        for dest in &dests {
+            ancestors.extend(dest.ancestors().skip(1));
+        }
+        let own: Vec<&PathBuf> = graveyards.iter().filter(|g| self.owns(g)).collect();
+        for graveyard in &own {
+            if graveyard.is_dir() {
+                find_orphans(
+                    graveyard,
+                    graveyard,
+                    &dests,
+                    &ancestors,
+                    &graveyards,
+                    &mut report.orphans,
+                )?;
+            }
+        }
+
+        if repair && report.problems() > 0 {
+            // Graves are kept at their original absolute path under the
+            // graveyard, so that's where an orphan came from
+            for orphan in &report.orphans {
+                if let Some(relative) = own.iter().find_map(|g| orphan.strip_prefix(g).ok()) {
+                    let orig = Path::new("/").join(relative);
+                    keep.push(Grave::new(orig, orphan, &[String::from("fsck=adopted")]));
+                }
+            }
+            self.record().replace(&keep)?;
+            let mut gone = report.dangling.clone();
+            gone.extend(report.duplicates.iter().cloned());
+            self.history().archive(&gone, "fsck")?;
+        }
+
+        Ok(report)
+    }
+}
+
+/// Collect everything under dir that's neither a recorded grave nor on the
+/// way to one, without descending into other graveyards
+fn find_orphans(
+    dir: &Path,
+    graveyard: &Path,
+    dests: &HashSet<&Path>,
+    ancestors: &HashSet<&Path>,
+    graveyards: &[PathBuf],
+    orphans: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir).chain_err(|| format!("Couldn't read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .chain_err(|| format!("Couldn't read {}", dir.display()))?
+            .path();
+        if dir == graveyard && is_bookkeeping(path.file_name()) {
+            continue;
+        }
+        if dests.contains(path.as_path()) || graveyards.contains(&path) {
+            continue;
+        }
+        let is_dir = fs::symlink_metadata(&path)
+            .map(|m| m.is_dir())
+            .unwrap_or(false);
+        if is_dir && ancestors.contains(path.as_path()) {
+            find_orphans(&path, graveyard, dests, ancestors, graveyards, orphans)?;
+        } else if !is_dir || !is_empty_tree(&path) {
+            // Unburying leaves empty directories behind, which don't count
+            orphans.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn is_empty_tree(dir: &Path) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .all(|e| e.is_ok_and(|e| e.file_type().is_dir()))
+}
+
+/// Files rip keeps at the top of a graveyard for itself
+fn is_bookkeeping(name: Option<&OsStr>) -> bool {
+    let name = match name.and_then(OsStr::to_str) {
+        Some(name) => name,
+        None => return false,
+    };
+    name.starts_with(RECORD) || name == JOURNAL || name == GRAVEYARDS || name == HISTORY
+}
@@ -0,0 +1,985 @@
+//! Graveyards: where removed files go to rest.
+
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::{env, fs, io};
+
+use crate::bury::{bury, tree_size, unlink, BigFiles, BuryOptions, Tombstone};
+use crate::errors::*;
+use crate::history::{History, HISTORY};
+use crate::journal::{remove_any, uninterrupted, Journal, JOURNAL};
+use crate::notice::{Notice, Question};
+use crate::policy::{Decision, Policy};
+use crate::record::{
+    format_field, open_store, Grave, GraveFilter, RecordStore, StoreKind, TOMBSTONE,
+};
+use crate::{checksum, join_absolute, mounts, rename_grave, symlink_exists, Owner};
+
+/// Index of per-filesystem graveyards, kept in the main graveyard
+pub(crate) const GRAVEYARDS: &str = ".graveyards";
+
+/// Directories that are never removed without `no_preserve_root`, along
+/// with $HOME and any mount point
+const PROTECTED: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/home", "/lib", "/lib32", "/lib64", "/opt", "/proc",
+    "/root", "/run", "/sbin", "/srv", "/sys", "/usr", "/var",
+];
+
+/// Who graveyards and the files we keep in them belong to, which under sudo
+/// is the user who ran it rather than root.  Set once at startup.
+static OWNER: OnceLock<Owner> = OnceLock::new();
+
+/// Keep graveyards for owner rather than the current user, e.g. for the user
+/// who ran sudo.  Only the first call has any effect.
+pub fn set_owner(owner: Owner) {
+    OWNER.set(owner).ok();
+}
+
+/// The main graveyard, along with any per-filesystem or per-tree graveyards
+/// registered in it.  Graves in any of them are logged in the main
+/// graveyard's record.
+pub struct Graveyard {
+    path: PathBuf,
+    record: Box<dyn RecordStore>,
+    history: History,
+    journal: Journal,
+}
+
+/// Result of checking graves against their stored checksums
+#[derive(Default)]
+pub struct Verification {
+    pub intact: usize,
+    /// Graves buried without --checksum
+    pub unchecked: usize,
+    pub damaged: Vec<(PathBuf, Damage)>,
+}
+
+pub enum Damage {
+    Modified,
+    Missing(io::Error),
+}
+
+impl Graveyard {
+    /// Open the graveyard at path, creating it if need be.  See
+    /// `ensure_graveyard` for the graveyards we refuse to use.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Graveyard> {
+        Graveyard::open_with(path, StoreKind::Text)
+    }
+
+    /// Open the graveyard at path, keeping the record in a store of the
+    /// given kind.  A graveyard that already has a record of the other kind
+    /// keeps using it until it's migrated.
+    pub fn open_with<P: AsRef<Path>>(path: P, store: StoreKind) -> Result<Graveyard> {
+        // Graves are looked up by absolute path, so the graveyard has to be
+        // one too
+        let path = env::current_dir()
+            .chain_err(|| "Failed to get current dir")?
+            .join(path);
+        ensure_graveyard(&path)?;
+        Ok(Graveyard {
+            record: open_store(&path, store.existing(&path))?,
+            history: History::new(path.join(HISTORY)),
+            journal: Journal::new(&path),
+            path,
+        })
+    }
+
+    /// Open the graveyard at path as it is, to look through it.  Unlike
+    /// `open_with`, nothing is created or has its permissions changed, and
+    /// it's an error for the graveyard or its record not to be there.
+    pub fn open_existing<P: AsRef<Path>>(path: P, store: StoreKind) -> Result<Graveyard> {
+        let path = env::current_dir()
+            .chain_err(|| "Failed to get current dir")?
+            .join(path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                bail!(ErrorKind::NotFound(path))
+            }
+            Err(e) => {
+                return Err(e).chain_err(|| format!("Couldn't check graveyard {}", path.display()))
+            }
+        };
+        if let Some(problem) = untrusted(&metadata) {
+            bail!(ErrorKind::UntrustedGraveyard(path, problem));
+        }
+        let kind = store.existing(&path);
+        if !path.join(kind.file_name()).is_file() {
+            bail!(ErrorKind::NotFound(path.join(kind.file_name())));
+        }
+        Ok(Graveyard {
+            record: open_store(&path, kind)?,
+            history: History::new(path.join(HISTORY)),
+            journal: Journal::new(&path),
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self) -> &dyn RecordStore {
+        &*self.record
+    }
+
+    /// Entries that have been purged from the record
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub(crate) fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    /// Return the main graveyard followed by any others listed in its index
+    pub fn all(&self) -> Vec<PathBuf> {
+        let mut graveyards = vec![self.path.clone()];
+        if let Ok(f) = fs::File::open(self.path.join(GRAVEYARDS)) {
+            graveyards.extend(
+                BufReader::new(f)
+                    .lines()
+                    .map_while(|l| l.ok())
+                    .filter(|l| !l.is_empty())
+                    .map(PathBuf::from),
+            );
+        }
+        graveyards
+    }
+
+    /// Revert or complete any moves left over from an interrupted run,
+    /// telling `opts.notify` about each
+    pub fn recover(&self, opts: &BuryOptions) -> Result<()> {
+        self.journal.recover(&*self.record, &self.all(), opts)
+    }
+
+    /// Move the record into a store of another kind, returning how many
+    /// entries were moved
+    pub fn migrate_record(&mut self, kind: StoreKind) -> Result<usize> {
+        if self.record.kind() == kind {
+            return Ok(0);
+        }
+        let graves = self.record.graves()?;
+        let store = open_store(&self.path, kind)?;
+        if !store.graves()?.is_empty() {
+            bail!(
+                "{} already has entries; move it out of the way first",
+                store.path().display()
+            );
+        }
+        store.append(&graves)?;
+        let old = self.record.path().to_owned();
+        self.record = store;
+        if let Err(e) = fs::remove_file(&old) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e)
+                    .chain_err(|| format!("Couldn't remove old record {}", old.display()));
+            }
+        }
+        Ok(graves.len())
+    }
+
+    /// Move the graveyard to dest, graves, record and all, and rewrite the
+    /// entries that still have an absolute path into it.  Returns how many
+    /// were rewritten.
+    pub fn migrate_graveyard<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        opts: &BuryOptions,
+    ) -> Result<usize> {
+        let dest = env::current_dir()
+            .chain_err(|| "Failed to get current dir")?
+            .join(dest);
+        if dest.starts_with(&self.path) {
+            bail!("Can't move the graveyard into itself");
+        }
+        if symlink_exists(&dest) && fs::read_dir(&dest).map_or(true, |mut d| d.next().is_some()) {
+            bail!("{} already exists", dest.display());
+        }
+        // Don't carry a half-finished move along, and make sure the record
+        // can be rewritten before moving anything
+        self.recover(opts)?;
+        self.record.graves()?;
+
+        let old = self.path.clone();
+        if let Some(parent) = dest.parent() {
+            create_dir_all_owned(parent, 0o777).chain_err(|| "Couldn't create parent dir")?;
+        }
+        match fs::rename(&old, &dest) {
+            Ok(()) => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                // Across filesystems, copy everything over but the journal,
+                // which keeps track of the copies in case we're interrupted
+                ensure_graveyard(&dest)?;
+                let opts = &BuryOptions {
+                    permanent: false,
+                    shred: None,
+                    big_files: BigFiles::Bury,
+                    ..*opts
+                };
+                let failed = || format!("Couldn't read graveyard {}", old.display());
+                for entry in fs::read_dir(&old).chain_err(failed)? {
+                    let source = entry.chain_err(failed)?.path();
+                    let name = match source.file_name() {
+                        Some(name) if name != OsStr::new(JOURNAL) => name,
+                        _ => continue,
+                    };
+                    let to = dest.join(name);
+                    bury(&source, &to, opts, &self.journal).chain_err(|| {
+                        format!("Couldn't move {} to {}", source.display(), to.display())
+                    })?;
+                    self.journal.finish(&to).ok();
+                }
+                remove_any(&old)
+                    .chain_err(|| format!("Couldn't remove old graveyard {}", old.display()))?;
+            }
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Couldn't move {} to {}", old.display(), dest.display()))
+            }
+        }
+        *self = Graveyard::open_with(&dest, self.record.kind())?;
+
+        let mut rewritten = 0;
+        let mut graves = self.record.graves()?;
+        for grave in &mut graves {
+            if let Ok(rest) = grave.dest.strip_prefix(&old) {
+                grave.dest = self.path.join(rest);
+                rewritten += 1;
+            }
+        }
+        if rewritten > 0 {
+            self.record.replace(&graves)?;
+        }
+        Ok(rewritten)
+    }
+
+    /// Every grave and tombstone in the record, oldest first
+    pub fn list(&self) -> Result<Vec<Grave>> {
+        self.record.graves()
+    }
+
+    /// Everything removed from under dir, oldest first
+    pub fn seance<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<Grave>> {
+        self.record.seance(dir.as_ref())
+    }
+
+    /// The latest grave at dest, if there is one
+    pub fn find<P: AsRef<Path>>(&self, dest: P) -> Result<Option<Grave>> {
+        self.record.find(dest.as_ref())
+    }
+
+    /// The last thing buried that's still here, if anything
+    pub fn last(&self) -> Result<Option<Grave>> {
+        self.record.last_bury()
+    }
+
+    /// Check that target could be buried without touching it: that it's
+    /// there, isn't protected by `opts` or a `.riprc`, and that the `.riprc`
+    /// is trusted and doesn't want it unlinked when `opts.reversible` is set
+    pub fn check<P: AsRef<Path>>(&self, target: P, opts: &BuryOptions) -> Result<()> {
+        let cwd = env::current_dir().chain_err(|| "Failed to get current dir")?;
+        let (_, source) = locate(target.as_ref(), &cwd)?;
+        let policy = Policy::find(&source)?;
+        let decision = policy.as_ref().map(|p| p.decide(&source));
+        check_protected(&source, decision.as_ref(), opts)?;
+        let permanent = opts.permanent || decision.is_some_and(|d| d.permanent || d.shred);
+        if permanent && opts.reversible {
+            bail!(irreversible(&source));
+        }
+        Ok(())
+    }
+
+    /// Send target to the graveyard and record it, returning the graves it
+    /// went into: usually one, several if parts of it had to be left behind,
+    /// and none if it was skipped or unlinked outright.  If part of the
+    /// target fails, the parts already buried are returned to where they were.
+    pub fn bury<P: AsRef<Path>>(&self, target: P, opts: &BuryOptions) -> Result<Vec<Grave>> {
+        let cwd = env::current_dir().chain_err(|| "Failed to get current dir")?;
+        let mut graves = Vec::new();
+        let e = match self.bury_target(target.as_ref(), &cwd, opts, &mut graves) {
+            Ok(()) => return Ok(graves),
+            Err(e) => e,
+        };
+        for grave in graves.iter().rev() {
+            if let Err(r) = uninterrupted(|| self.unbury(grave, opts)) {
+                return Err(e).chain_err(|| {
+                    format!(
+                        "Couldn't return {} to {}: {}",
+                        grave.dest.display(),
+                        grave.orig.display(),
+                        r
+                    )
+                });
+            }
+        }
+        Err(e)
+    }
+
+    /// Return a grave to where it came from, or next to it if something
+    /// else is there now, and remove it from the record.  Returns where it
+    /// went.
+    pub fn unbury(&self, grave: &Grave, opts: &BuryOptions) -> Result<PathBuf> {
+        let orig = if symlink_exists(&grave.orig) {
+            rename_grave(&grave.orig)
+        } else {
+            grave.orig.clone()
+        };
+        bury(&grave.dest, &orig, opts, &self.journal).chain_err(|| {
+            format!(
+                "Unbury failed: couldn't copy files from {} to {}",
+                grave.dest.display(),
+                orig.display()
+            )
+        })?;
+        self.record
+            .remove(std::slice::from_ref(&grave.dest))
+            .chain_err(|| "Failed to remove unburied files from record")?;
+        self.journal.finish(&orig).ok();
+        Ok(orig)
+    }
+
+    /// Unlink a grave for good, moving its entry from the record to the
+    /// history
+    pub fn purge(&self, grave: &Grave, opts: &BuryOptions) -> Result<()> {
+        if !grave.is_tombstone() {
+            unlink(&grave.dest, opts)
+                .chain_err(|| format!("Couldn't unlink {}", grave.dest.display()))?;
+        }
+        self.retire(std::slice::from_ref(grave), "purge")
+    }
+
+    /// Purge graves that were buried more than days ago, returning how many
+    /// there were.  Tombstones that old are moved to the history too.
+    pub fn purge_older_than(&self, days: u64, opts: &BuryOptions) -> Result<usize> {
+        let cutoff = crate::record::now().saturating_sub((days * 24 * 60 * 60) as i64);
+        self.purge_where(
+            |g| g.recorded_at().is_some_and(|t| t < cutoff),
+            "retention",
+            opts,
+        )
+    }
+
+    /// Purge the graves, and drop the tombstones, that the filter lets
+    /// through, returning how many graves there were
+    pub fn purge_matching(&self, filter: &GraveFilter, opts: &BuryOptions) -> Result<usize> {
+        self.purge_where(|g| filter.matches(g), "decompose", opts)
+    }
+
+    fn purge_where<F>(&self, pred: F, reason: &str, opts: &BuryOptions) -> Result<usize>
+    where
+        F: Fn(&Grave) -> bool,
+    {
+        let mut expired: Vec<Grave> = Vec::new();
+        let mut result = Ok(());
+        for grave in self.record.graves()? {
+            if !pred(&grave) {
+                continue;
+            }
+            if !grave.is_tombstone() {
+                if let Err(e) = unlink(&grave.dest, opts) {
+                    // Still retire what's already gone
+                    result =
+                        Err(e).chain_err(|| format!("Couldn't decompose {}", grave.dest.display()));
+                    break;
+                }
+            }
+            expired.push(grave);
+        }
+        self.retire(&expired, reason)?;
+        result.map(|()| expired.iter().filter(|g| !g.is_tombstone()).count())
+    }
+
+    /// Unlink every graveyard, record and all, keeping only the history,
+    /// which gets everything that was in the record
+    pub fn decompose(&self, opts: &BuryOptions) -> Result<()> {
+        let (graves, _) = self.record.scan()?;
+        self.history.archive(&graves, "decompose")?;
+        // Per-filesystem graveyards first, since the index of them is kept
+        // in the main graveyard
+        for g in self.all().iter().rev() {
+            if g != &self.path {
+                self.unlink_graves_in(g, &graves, opts)?;
+                continue;
+            }
+            let entries =
+                fs::read_dir(g).chain_err(|| format!("Couldn't read graveyard {}", g.display()))?;
+            for entry in entries {
+                let path = entry
+                    .chain_err(|| format!("Couldn't read graveyard {}", g.display()))?
+                    .path();
+                if path != self.history.path() {
+                    unlink(&path, opts)
+                        .chain_err(|| format!("Couldn't unlink {}", path.display()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move entries from the record to the history
+    fn retire(&self, graves: &[Grave], reason: &str) -> Result<()> {
+        if graves.is_empty() {
+            return Ok(());
+        }
+        self.history.archive(graves, reason)?;
+        // Tombstones all share a dest, so those have to be picked out exactly
+        if graves.iter().any(Grave::is_tombstone) {
+            let mut remaining = self.record.graves()?;
+            remaining.retain(|g| !graves.contains(g));
+            self.record.replace(&remaining)
+        } else {
+            let dests: Vec<PathBuf> = graves.iter().map(|g| g.dest.clone()).collect();
+            self.record.remove(&dests)
+        }
+    }
+
+    /// Check every grave that has a stored checksum
+    pub fn verify(&self) -> Result<Verification> {
+        let mut result = Verification::default();
+        for grave in self.list()? {
+            if grave.is_tombstone() {
+                continue;
+            }
+            let expected = match grave.field(checksum::FIELD) {
+                Some(sum) => sum,
+                None => {
+                    result.unchecked += 1;
+                    continue;
+                }
+            };
+            match checksum::tree_checksum(&grave.dest) {
+                Ok(ref sum) if *sum == expected => result.intact += 1,
+                Ok(_) => result.damaged.push((grave.dest, Damage::Modified)),
+                Err(e) => result.damaged.push((grave.dest, Damage::Missing(e))),
+            }
+        }
+        Ok(result)
+    }
+
+    fn bury_target(
+        &self,
+        target: &Path,
+        cwd: &Path,
+        opts: &BuryOptions,
+        buried: &mut Vec<Grave>,
+    ) -> Result<()> {
+        let (metadata, source) = locate(target, cwd)?;
+        let source = &source;
+
+        // A .riprc above the target can change where and how it's removed
+        let policy = Policy::find(source)?;
+        let decision = policy.as_ref().map(|p| p.decide(source));
+        if opts.verbose {
+            opts.tell(Notice::Policy(
+                source.to_owned(),
+                decision.as_ref().map(|d| d.to_string()),
+            ));
+        }
+        let opts = &match decision {
+            Some(ref d) => BuryOptions {
+                // Shredding is only worth anything if no copy is kept
+                permanent: opts.permanent || d.permanent || d.shred,
+                shred: if d.shred {
+                    opts.shred.or(Some(opts.shred_passes))
+                } else {
+                    opts.shred
+                },
+                ..*opts
+            },
+            None => *opts,
+        };
+
+        check_protected(source, decision.as_ref(), opts)?;
+
+        // Graves from a tree with its own graveyard go there, but are still
+        // recorded in the main graveyard
+        let tree_graveyard = policy.as_ref().and_then(|p| p.graveyard.as_ref());
+        if let Some(g) = tree_graveyard {
+            ensure_graveyard(g)?;
+            self.register(g)
+                .chain_err(|| format!("Couldn't register graveyard {}", g.display()))?;
+        }
+
+        // Compare against the real paths of the graveyards, since the source
+        // has been canonicalized
+        let graveyards: Vec<PathBuf> = self
+            .all()
+            .iter()
+            .map(|g| g.canonicalize().unwrap_or_else(|_| cwd.join(g)))
+            .collect();
+
+        // If rip is called on a file already in the graveyard, offer to
+        // permanently delete it instead.
+        if graveyards.iter().any(|g| source.starts_with(g)) {
+            if opts.reversible {
+                bail!(
+                    "Not unlinking {}, which is already in the graveyard, since that can't be undone",
+                    source.display()
+                );
+            }
+            if opts.confirm(Question::UnlinkGrave(source)) {
+                match self.find(source)? {
+                    Some(grave) => self.purge(&grave, opts)?,
+                    None => unlink(source, opts).chain_err(|| "Couldn't unlink")?,
+                }
+            } else {
+                opts.tell(Notice::Skipped(source.to_owned()));
+            }
+            return Ok(());
+        }
+
+        // Don't try to move the graveyard into itself
+        if graveyards.iter().any(|g| g.starts_with(source)) {
+            if !opts.confirm(Question::BuryAroundGraveyard(source)) {
+                bail!(ErrorKind::Declined(format!(
+                    "Not removing {}, which contains the graveyard",
+                    target.display()
+                )));
+            }
+            return self.bury_around(source, &graveyards, cwd, opts, buried);
+        }
+
+        // Leave other filesystems mounted inside the target where they are
+        if opts.one_file_system && metadata.is_dir() {
+            let mount_points = mounts::mount_points_under(source);
+            if !mount_points.is_empty() {
+                for m in &mount_points {
+                    opts.tell(Notice::SkippedMountPoint(m.clone()));
+                }
+                return self.bury_around(source, &mount_points, cwd, opts, buried);
+            }
+        }
+
+        if opts.permanent {
+            if opts.reversible {
+                bail!(irreversible(source));
+            }
+            let (size, _) = tree_size(source);
+            unlink(source, opts).chain_err(|| format!("Couldn't unlink {}", source.display()))?;
+            self.write_tombstones(
+                &[Tombstone {
+                    path: source.to_owned(),
+                    size,
+                    reason: "permanent",
+                }],
+                opts,
+            )?;
+            return Ok(());
+        }
+
+        let dest: &Path = &{
+            let grave_root = if let Some(g) = tree_graveyard {
+                g.to_owned()
+            } else if opts.per_filesystem_graveyards {
+                self.graveyard_for(source, opts)
+            } else {
+                self.path.clone()
+            };
+            let dest = join_absolute(grave_root, source);
+            // Resolve a name conflict if necessary
+            if symlink_exists(&dest) {
+                rename_grave(dest)
+            } else {
+                dest
+            }
+        };
+
+        if let Some(parent) = dest.parent() {
+            create_dir_all_owned(parent, 0o777).chain_err(|| "Couldn't create parent dir")?;
+        }
+        let tombstones =
+            bury(source, dest, opts, &self.journal).chain_err(|| "Failed to bury file")?;
+        self.write_tombstones(&tombstones, opts)?;
+        // The whole target may have been destroyed rather than buried
+        if !symlink_exists(dest) {
+            self.journal.finish(dest).ok();
+            return Ok(());
+        }
+        let mut fields: Vec<String> = Vec::new();
+        // It's in the graveyard now, so record it even without a checksum
+        if opts.checksum {
+            match checksum::tree_checksum(dest) {
+                Ok(sum) => fields.push(format!("{}={}", checksum::FIELD, sum)),
+                Err(e) => opts.tell(Notice::NoChecksum(dest.to_owned(), e.to_string())),
+            }
+        }
+        // What it was, as of just before it was buried
+        let size = if metadata.is_dir() {
+            tree_size(dest).0
+        } else {
+            metadata.len()
+        };
+        fields.extend(describe(&metadata, size));
+        fields.extend(annotations(opts));
+        let grave = Grave::new(source, dest, &fields);
+        self.record.append(std::slice::from_ref(&grave))?;
+        self.journal
+            .finish(dest)
+            .chain_err(|| "Couldn't update the journal")?;
+        buried.push(grave);
+        Ok(())
+    }
+
+    /// Bury everything inside dir except the protected paths, descending into
+    /// any directories that contain them.
+    fn bury_around(
+        &self,
+        dir: &Path,
+        protected: &[PathBuf],
+        cwd: &Path,
+        opts: &BuryOptions,
+        buried: &mut Vec<Grave>,
+    ) -> Result<()> {
+        let entries = fs::read_dir(dir).chain_err(|| format!("Couldn't read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry
+                .chain_err(|| format!("Couldn't read {}", dir.display()))?
+                .path();
+            if protected.contains(&path) {
+                continue;
+            }
+            if protected.iter().any(|p| p.starts_with(&path)) {
+                self.bury_around(&path, protected, cwd, opts, buried)?;
+            } else {
+                self.bury_target(&path, cwd, opts, buried)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pick the graveyard to bury source in.  Moving a file within a
+    /// filesystem is a cheap rename, so if source isn't on the same
+    /// filesystem as the main graveyard, use a graveyard of its own under
+    /// `<mount root>/.graveyard-$UID` instead, falling back to the main
+    /// graveyard if that can't be created, as on a read-only filesystem or
+    /// one whose root we can't write to.
+    fn graveyard_for(&self, source: &Path, opts: &BuryOptions) -> PathBuf {
+        let parent = source.parent().unwrap_or(source);
+        if mounts::device(parent) == mounts::device(&self.path) {
+            return self.path.clone();
+        }
+        let root = match mounts::mount_root(parent) {
+            Some(root) => root,
+            None => return self.path.clone(),
+        };
+        // Each main graveyard gets its own, so that decomposing or repairing
+        // one leaves the graves of the others alone
+        let shared = root.join(shared_graveyard_name());
+        let fs_graveyard = shared.join(fs_graveyard_name(&self.path));
+        if let Err(e) = ensure_graveyard(&shared).and_then(|_| ensure_graveyard(&fs_graveyard)) {
+            // Somebody may have planted it, so make some noise
+            if let ErrorKind::UntrustedGraveyard(ref path, ref problem) = *e.kind() {
+                opts.tell(Notice::UntrustedGraveyard(path.clone(), problem.clone()));
+            }
+            return self.path.clone();
+        }
+        if self.register(&fs_graveyard).is_err() {
+            return self.path.clone();
+        }
+        fs_graveyard
+    }
+
+    /// Whether graveyard only ever holds this main graveyard's graves, as
+    /// opposed to a tree's graveyard, or a `.graveyard-$UID` that older
+    /// versions shared between every main graveyard
+    pub(crate) fn owns(&self, graveyard: &Path) -> bool {
+        graveyard == self.path
+            || graveyard.parent().and_then(Path::file_name)
+                == Some(OsStr::new(&shared_graveyard_name()))
+    }
+
+    /// Add another graveyard to the main graveyard's index
+    fn register(&self, graveyard: &Path) -> io::Result<()> {
+        if self.all().iter().any(|g| g == graveyard) {
+            return Ok(());
+        }
+        let index = self.path.join(GRAVEYARDS);
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index)?;
+        writeln!(f, "{}", graveyard.display())?;
+        chown_to_owner(index)
+    }
+
+    /// Unlink this record's graves in another graveyard, which may hold
+    /// graves of other main graveyards too, then the dirs they leave empty
+    fn unlink_graves_in(
+        &self,
+        graveyard: &Path,
+        graves: &[Grave],
+        opts: &BuryOptions,
+    ) -> Result<()> {
+        for grave in graves {
+            if grave.is_tombstone() || !grave.dest.starts_with(graveyard) {
+                continue;
+            }
+            unlink(&grave.dest, opts)
+                .chain_err(|| format!("Couldn't unlink {}", grave.dest.display()))?;
+            for dir in grave.dest.ancestors().skip(1) {
+                if !dir.starts_with(graveyard) || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record files that were destroyed instead of buried
+    fn write_tombstones(&self, tombstones: &[Tombstone], opts: &BuryOptions) -> Result<()> {
+        let graves: Vec<Grave> = tombstones
+            .iter()
+            .map(|t| {
+                let mut fields = vec![
+                    format!("tombstone={}", t.reason),
+                    format!("size={}", t.size),
+                ];
+                fields.extend(annotations(opts));
+                Grave::new(&t.path, TOMBSTONE, &fields)
+            })
+            .collect();
+        if graves.is_empty() {
+            return Ok(());
+        }
+        self.record.append(&graves)
+    }
+}
+
+/// Name of the dir at the root of a filesystem that holds its per-filesystem
+/// graveyards
+fn shared_graveyard_name() -> String {
+    format!(".graveyard-{}", owner().uid)
+}
+
+/// Name of the dir under a filesystem's `.graveyard-$UID` that holds the
+/// graves of the main graveyard at path
+fn fs_graveyard_name(path: &Path) -> String {
+    blake3::hash(path.as_os_str().as_bytes()).to_hex()[..16].to_owned()
+}
+
+/// What a grave was, as of just before it was buried, as fields for the
+/// record
+pub(crate) fn describe(metadata: &fs::Metadata, size: u64) -> Vec<String> {
+    vec![
+        format!("type={}", type_name(metadata.file_type())),
+        format!("size={}", size),
+        format!("mode={:o}", metadata.mode() & 0o7777),
+        format!("uid={}", metadata.uid()),
+        format!("gid={}", metadata.gid()),
+    ]
+}
+
+/// The message and tags to record with everything removed
+pub(crate) fn annotations(opts: &BuryOptions) -> Vec<String> {
+    let mut fields = Vec::new();
+    if let Some(message) = opts.message {
+        fields.push(format_field("message", message));
+    }
+    if !opts.tags.is_empty() {
+        fields.push(format_field("tags", &opts.tags.join(",")));
+    }
+    fields
+}
+
+/// Create a graveyard that only we can get into if it doesn't exist, and
+/// refuse to use one that someone else could have planted or tampered with:
+/// a symlink or a directory owned by another user.  One of ours that other
+/// users can write to is made private again.
+fn ensure_graveyard(graveyard: &Path) -> Result<()> {
+    if let Err(e) = create_dir_all_owned(graveyard, 0o700) {
+        // A dangling symlink makes the create fail, so check for one below
+        if !symlink_exists(graveyard) {
+            return Err(e)
+                .chain_err(|| format!("Couldn't create graveyard {}", graveyard.display()));
+        }
+    }
+    let metadata = fs::symlink_metadata(graveyard)
+        .chain_err(|| format!("Couldn't check graveyard {}", graveyard.display()))?;
+    if let Some(problem) = untrusted(&metadata) {
+        bail!(ErrorKind::UntrustedGraveyard(graveyard.to_owned(), problem));
+    }
+    if metadata.mode() & 0o022 != 0 {
+        // Ours, but made by an older rip under a loose umask, so close it
+        // up rather than refuse it
+        return fs::set_permissions(graveyard, fs::Permissions::from_mode(0o700))
+            .chain_err(|| format!("Couldn't restrict graveyard {}", graveyard.display()));
+    }
+    Ok(())
+}
+
+/// Why a graveyard with this metadata isn't one we can use, if it isn't
+fn untrusted(metadata: &fs::Metadata) -> Option<String> {
+    let uid = owner().uid;
+    if metadata.file_type().is_symlink() {
+        Some(String::from("it's a symlink"))
+    } else if !metadata.is_dir() {
+        Some(String::from("it's not a directory"))
+    } else if metadata.uid() != uid {
+        Some(format!("it's owned by uid {}, not {}", metadata.uid(), uid))
+    } else {
+        None
+    }
+}
+
+fn type_name(file_type: fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block"
+    } else if file_type.is_char_device() {
+        "char"
+    } else {
+        "file"
+    }
+}
+
+pub(crate) fn owner() -> Owner {
+    OWNER.get().copied().unwrap_or_else(Owner::current)
+}
+
+/// Give something we created in a graveyard to the graveyard's owner, if
+/// we're root acting for someone else
+pub(crate) fn chown_to_owner<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let owner = owner();
+    if owner.uid == unsafe { libc::geteuid() } {
+        return Ok(());
+    }
+    std::os::unix::fs::lchown(path, Some(owner.uid), Some(owner.gid))
+}
+
+/// Create dir and any missing parents with the given mode, handing the new
+/// ones over to the graveyard's owner
+pub(crate) fn create_dir_all_owned(dir: &Path, mode: u32) -> io::Result<()> {
+    let missing: Vec<&Path> = dir.ancestors().take_while(|p| !symlink_exists(p)).collect();
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(mode)
+        .create(dir)?;
+    for p in missing.iter().rev() {
+        chown_to_owner(p)?;
+    }
+    Ok(())
+}
+
+/// Look up target's metadata, and its absolute path, canonicalized unless
+/// it's a symlink
+fn locate(target: &Path, cwd: &Path) -> Result<(fs::Metadata, PathBuf)> {
+    // Check if source exists
+    let metadata = match fs::symlink_metadata(target) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            bail!(ErrorKind::PermissionDenied(target.to_owned()))
+        }
+        Err(_) => bail!(ErrorKind::NotFound(target.to_owned())),
+    };
+
+    // Canonicalize the path unless it's a symlink
+    let source = if !metadata.file_type().is_symlink() {
+        cwd.join(target)
+            .canonicalize()
+            .chain_err(|| "Failed to canonicalize path")?
+    } else {
+        cwd.join(target)
+    };
+    Ok((metadata, source))
+}
+
+/// Refuse source if it's protected, unless `no_preserve_root` is set
+fn check_protected(source: &Path, decision: Option<&Decision>, opts: &BuryOptions) -> Result<()> {
+    if opts.no_preserve_root {
+        return Ok(());
+    }
+    if let Some(reason) = protected_reason(source, opts.protected) {
+        bail!(ErrorKind::Protected(source.to_owned(), reason.to_owned()));
+    }
+    if let Some(d) = decision {
+        if let Some(pattern) = d.protected_by {
+            bail!(ErrorKind::Protected(
+                source.to_owned(),
+                format!("it matches {:?} in {}", pattern, d.policy.path.display())
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn irreversible(source: &Path) -> String {
+    format!(
+        "Not unlinking {} for good, since that can't be undone",
+        source.display()
+    )
+}
+
+/// If path is one that shouldn't be removed without `no_preserve_root`,
+/// return why
+fn protected_reason(path: &Path, extra: &[PathBuf]) -> Option<&'static str> {
+    let is = |p: &Path| p.canonicalize().map(|p| p == path).unwrap_or(p == path);
+    if PROTECTED.iter().any(|p| is(Path::new(p))) {
+        Some("it's a system directory")
+    } else if env::var_os("HOME").is_some_and(|home| is(Path::new(&home))) {
+        Some("it's your home directory")
+    } else if extra.iter().any(|p| is(p)) {
+        Some("it's a protected path")
+    } else if mounts::is_mount_point(path) {
+        Some("it's a mount point")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch_dir;
+
+    #[test]
+    fn migrating_moves_dests_under_the_new_root() {
+        let dir = scratch_dir("graveyard-migrate");
+        let old = dir.join("old");
+        let new = dir.join("new");
+        let mut graveyard = Graveyard::open(&old).unwrap();
+        fs::create_dir_all(old.join("w")).unwrap();
+        fs::write(old.join("w/a"), "a").unwrap();
+        fs::write(old.join("w/b"), "b").unwrap();
+        graveyard
+            .record()
+            .append(&[Grave::new("/w/a", old.join("w/a"), &[])])
+            .unwrap();
+        // Records from before dests were relative have absolute ones
+        let legacy = Grave::new("/w/b", old.join("w/b"), &[]).to_line();
+        let mut f = fs::OpenOptions::new()
+            .append(true)
+            .open(graveyard.record().path())
+            .unwrap();
+        writeln!(f, "{}", legacy).unwrap();
+        drop(f);
+
+        let rewritten = graveyard
+            .migrate_graveyard(&new, &BuryOptions::default())
+            .unwrap();
+
+        assert_eq!(rewritten, 1);
+        assert_eq!(graveyard.path(), new.as_path());
+        let dests: Vec<PathBuf> = graveyard
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|g| g.dest)
+            .collect();
+        assert_eq!(dests, vec![new.join("w/a"), new.join("w/b")]);
+        assert!(!symlink_exists(&old));
+        let record = fs::read_to_string(graveyard.record().path()).unwrap();
+        assert!(!record.contains(old.to_str().unwrap()));
+    }
+}
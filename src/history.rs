@@ -0,0 +1,89 @@
+//! The history: an archive of entries that have left the record.
+//!
+//! When graves are purged or decomposed, their entries are appended to
+//! `.history.gz` in the main graveyard, noting when and why, so that the
+//! record only holds what's still around.  Each rotation appends another
+//! gzip member with the entries as lines in the text record's format.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use crate::chown_to_owner;
+use crate::errors::*;
+use crate::record::Grave;
+
+pub const HISTORY: &str = ".history.gz";
+
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(path: P) -> History {
+        History {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add graves that are leaving the record, noting why: `retention`,
+    /// `purge`, `decompose` or `fsck`
+    pub fn archive(&self, graves: &[Grave], reason: &str) -> Result<()> {
+        if graves.is_empty() {
+            return Ok(());
+        }
+        let when = time::now().ctime().to_string();
+        let write = || -> io::Result<()> {
+            let f = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let mut gz = GzEncoder::new(f, Compression::default());
+            for grave in graves {
+                writeln!(
+                    gz,
+                    "{}\tpurged={}\tpurged_at={}",
+                    grave.to_line(),
+                    reason,
+                    when
+                )?;
+            }
+            gz.finish()?.sync_all()?;
+            chown_to_owner(&self.path)
+        };
+        write().chain_err(|| format!("Failed to write history at {}", self.path.display()))
+    }
+
+    /// Every archived entry, oldest first
+    pub fn entries(&self) -> Result<Vec<Grave>> {
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Failed to read history at {}", self.path.display()))
+            }
+        };
+        let mut graves = Vec::new();
+        for line in BufReader::new(MultiGzDecoder::new(f)).lines() {
+            let line =
+                line.chain_err(|| format!("Failed to read history at {}", self.path.display()))?;
+            graves.extend(Grave::parse(&line));
+        }
+        Ok(graves)
+    }
+
+    /// Archived entries whose original path contains pattern
+    pub fn search(&self, pattern: &str) -> Result<Vec<Grave>> {
+        let mut graves = self.entries()?;
+        graves.retain(|g| g.orig.to_string_lossy().contains(pattern));
+        Ok(graves)
+    }
+}
@@ -0,0 +1,321 @@
+//! Write-ahead journal for copies across filesystems.
+//!
+//! A rename is atomic, but burying across mount points means copying the
+//! target into the graveyard and then removing the source, and rip can be
+//! killed anywhere in between.  Before each phase we append a line to
+//! `.journal` in the graveyard, and clear it once the record is updated.
+//! On the next run, `recover` reverts copies that never finished and
+//! completes removals that had already started.
+//!
+//! Each line is the phase, source and dest, followed by the fields the
+//! record would have gotten for a burial, so that a burial completed on
+//! the next run is recorded as who removed what, rather than as whoever
+//! happened to run rip next.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{fs, io};
+
+use crate::bury::BuryOptions;
+use crate::errors::*;
+use crate::notice::Notice;
+use crate::record::{Grave, RecordStore};
+use crate::{chown_to_owner, symlink_exists};
+
+pub(crate) const JOURNAL: &str = ".journal";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Phase of an interrupted move
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// Copying into dest; the source is still intact
+    Copy,
+    /// The copy finished and the source is being removed
+    Remove,
+}
+
+impl Phase {
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Copy => "copy",
+            Phase::Remove => "remove",
+        }
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new<G: AsRef<Path>>(graveyard: G) -> Journal {
+        Journal {
+            path: graveyard.as_ref().join(JOURNAL),
+        }
+    }
+
+    /// Note that we're about to start copying source to dest, with the
+    /// fields to record if it turns out to be a burial
+    pub fn begin<S, D>(&self, source: S, dest: D, fields: &[String]) -> io::Result<()>
+    where
+        S: AsRef<Path>,
+        D: AsRef<Path>,
+    {
+        self.append(Phase::Copy, source.as_ref(), dest.as_ref(), fields)
+    }
+
+    /// Note that dest is a complete copy and source is about to be removed
+    pub fn removing<S, D>(&self, source: S, dest: D, fields: &[String]) -> io::Result<()>
+    where
+        S: AsRef<Path>,
+        D: AsRef<Path>,
+    {
+        self.append(Phase::Remove, source.as_ref(), dest.as_ref(), fields)
+    }
+
+    /// Forget about a move that was either completed and recorded, or
+    /// cleanly rolled back.
+    pub fn finish<D: AsRef<Path>>(&self, dest: D) -> io::Result<()> {
+        let dest = dest.as_ref();
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let lines_to_write: Vec<String> = BufReader::new(f)
+            .lines()
+            .map_while(|l| l.ok())
+            .filter(|l| journal_entry(l).is_none_or(|(_, _, d, _)| d != dest))
+            .collect();
+        if lines_to_write.is_empty() {
+            return fs::remove_file(&self.path);
+        }
+        let mut f = fs::File::create(&self.path)?;
+        for line in lines_to_write {
+            writeln!(f, "{}", line)?;
+        }
+        f.sync_all()
+    }
+
+    /// Revert or complete any moves left over from an interrupted run,
+    /// updating record to match.  Moves into any of graveyards are burials;
+    /// anything else is an unbury.
+    pub fn recover(
+        &self,
+        record: &dyn RecordStore,
+        graveyards: &[PathBuf],
+        opts: &BuryOptions,
+    ) -> Result<()> {
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).chain_err(|| "Couldn't read the journal"),
+        };
+
+        // Only the latest phase of each move matters
+        let mut moves: Vec<(Phase, PathBuf, PathBuf, Vec<String>)> = Vec::new();
+        for line in BufReader::new(f).lines().map_while(|l| l.ok()) {
+            if let Some((phase, source, dest, fields)) = journal_entry(&line) {
+                moves.retain(|(_, _, d, _)| d != dest);
+                moves.push((phase, source.to_owned(), dest.to_owned(), fields));
+            }
+        }
+
+        for (phase, source, dest, mut fields) in moves {
+            if phase == Phase::Copy && symlink_exists(&source) {
+                remove_any(&dest)
+                    .chain_err(|| format!("Couldn't remove partial copy {}", dest.display()))?;
+                opts.tell(Notice::Reverted(source.clone(), dest.clone()));
+            } else {
+                remove_any(&source)
+                    .chain_err(|| format!("Couldn't finish removing {}", source.display()))?;
+                if graveyards.iter().any(|g| dest.starts_with(g)) {
+                    // Journals from before fields were kept have none, and
+                    // this run's pid, cwd and so on would be misleading
+                    fields.push(String::from("recovered=1"));
+                    record.append(&[Grave {
+                        time: time::now().ctime().to_string(),
+                        orig: source.clone(),
+                        dest: dest.clone(),
+                        fields,
+                    }])?;
+                } else {
+                    record
+                        .remove(std::slice::from_ref(&source))
+                        .chain_err(|| "Failed to remove unburied file from record")?;
+                }
+                opts.tell(Notice::Completed(source.clone(), dest.clone()));
+            }
+            self.finish(&dest)
+                .chain_err(|| "Couldn't update the journal")?;
+        }
+
+        Ok(())
+    }
+
+    fn append(
+        &self,
+        phase: Phase,
+        source: &Path,
+        dest: &Path,
+        fields: &[String],
+    ) -> io::Result<()> {
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        write!(
+            f,
+            "{}\t{}\t{}",
+            phase.as_str(),
+            source.display(),
+            dest.display()
+        )?;
+        for field in fields {
+            write!(f, "\t{}", field)?;
+        }
+        writeln!(f)?;
+        f.sync_all()?;
+        chown_to_owner(&self.path)
+    }
+}
+
+/// Parse a line in the journal into its phase, source, dest and fields
+fn journal_entry(line: &str) -> Option<(Phase, &Path, &Path, Vec<String>)> {
+    let mut tokens = line.split('\t');
+    let phase = match tokens.next()? {
+        "copy" => Phase::Copy,
+        "remove" => Phase::Remove,
+        _ => return None,
+    };
+    let source = Path::new(tokens.next()?);
+    let dest = Path::new(tokens.next()?);
+    Some((phase, source, dest, tokens.map(str::to_owned).collect()))
+}
+
+/// Remove a file or directory tree, succeeding if it's already gone
+pub fn remove_any<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// True if SIGINT was received while a `SigintGuard` was active
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Run f as if SIGINT hadn't been received, then remember it again.  An
+/// earlier SIGINT has already stopped the copy it interrupted, but returning
+/// what was buried so far should still go ahead, and afterwards the caller
+/// should still see that it was asked to stop.
+pub fn uninterrupted<T, F: FnOnce() -> T>(f: F) -> T {
+    let was = INTERRUPTED.swap(false, Ordering::SeqCst);
+    let result = f();
+    if was {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    result
+}
+
+extern "C" fn handle_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Catches SIGINT for as long as it lives, so that a copy can be stopped
+/// between files and rolled back instead of killing rip halfway through.
+pub struct SigintGuard {
+    previous: libc::sighandler_t,
+}
+
+impl SigintGuard {
+    /// Start catching SIGINT
+    pub fn install() -> SigintGuard {
+        let handler = handle_sigint as extern "C" fn(libc::c_int);
+        let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+        SigintGuard { previous }
+    }
+}
+
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::TextRecord;
+    use crate::scratch_dir;
+
+    #[test]
+    fn recover_reverts_an_unfinished_copy() {
+        let dir = scratch_dir("journal-copy");
+        let graveyard = dir.join("graveyard");
+        let source = dir.join("a");
+        let dest = graveyard.join("a");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("partial"), "half").unwrap();
+        fs::write(&source, "whole").unwrap();
+        let record = TextRecord::new(graveyard.join(".record"));
+        let journal = Journal::new(&graveyard);
+        journal.begin(&source, &dest, &[]).unwrap();
+
+        journal
+            .recover(
+                &record,
+                std::slice::from_ref(&graveyard),
+                &BuryOptions::default(),
+            )
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&source).unwrap(), "whole");
+        assert!(!symlink_exists(&dest));
+        assert!(record.graves().unwrap().is_empty());
+        assert!(!symlink_exists(graveyard.join(JOURNAL)));
+    }
+
+    #[test]
+    fn recover_finishes_a_started_removal() {
+        let dir = scratch_dir("journal-remove");
+        let graveyard = dir.join("graveyard");
+        let source = dir.join("a");
+        let dest = graveyard.join("a");
+        fs::create_dir_all(&graveyard).unwrap();
+        fs::write(&dest, "whole").unwrap();
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("left"), "over").unwrap();
+        let record = TextRecord::new(graveyard.join(".record"));
+        let journal = Journal::new(&graveyard);
+        let fields = [String::from("user=someone")];
+        journal.begin(&source, &dest, &fields).unwrap();
+        journal.removing(&source, &dest, &fields).unwrap();
+
+        journal
+            .recover(
+                &record,
+                std::slice::from_ref(&graveyard),
+                &BuryOptions::default(),
+            )
+            .unwrap();
+
+        assert!(!symlink_exists(&source));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "whole");
+        let graves = record.graves().unwrap();
+        assert_eq!(graves.len(), 1);
+        assert_eq!(graves[0].orig, source);
+        assert_eq!(graves[0].dest, dest);
+        assert_eq!(graves[0].field("user").as_deref(), Some("someone"));
+        assert_eq!(graves[0].field("recovered").as_deref(), Some("1"));
+        assert!(!symlink_exists(graveyard.join(JOURNAL)));
+    }
+}
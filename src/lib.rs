@@ -0,0 +1,97 @@
+//! Rm ImProved: send files to a graveyard instead of unlinking them.
+//!
+//! This is the library behind the `rip` command.  A [`Graveyard`] buries
+//! files and directories, logging each [`Grave`] in its [`RecordStore`], and can
+//! return them to where they came from or purge them for good.
+//!
+//! ```no_run
+//! use rm_improved::{BuryOptions, Graveyard};
+//!
+//! let graveyard = Graveyard::open("/tmp/graveyard-jack")?;
+//! let opts = BuryOptions::default();
+//! let graves = graveyard.bury("target/debug", &opts)?;
+//! for grave in &graves {
+//!     graveyard.unbury(grave, &opts)?;
+//! }
+//! # Ok::<(), rm_improved::errors::Error>(())
+//! ```
+#[macro_use]
+extern crate error_chain;
+
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{env, fs, io};
+
+mod bury;
+mod checksum;
+pub mod errors;
+mod fsck;
+mod graveyard;
+mod history;
+mod journal;
+mod mounts;
+mod notice;
+mod policy;
+mod progress;
+mod record;
+mod shred;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod trash;
+
+pub use bury::{
+    tree_size, Ask, BigFiles, BuryOptions, Notify, BIG_FILE_THRESHOLD, LOW_SPACE_PERCENT,
+    SHRED_PASSES,
+};
+pub use fsck::Fsck;
+pub use graveyard::{set_owner, Damage, Graveyard, Verification};
+pub use history::History;
+pub use journal::{interrupted, uninterrupted};
+pub use notice::{Notice, Question};
+pub use record::{Grave, GraveFilter, RecordStore, StoreKind, TextRecord, TOMBSTONE};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteRecord;
+pub use trash::{Trash, Trashed};
+
+use graveyard::{chown_to_owner, create_dir_all_owned};
+
+include!("util.rs");
+
+/// A fresh directory for a test to work in, removed again when dropped so
+/// that a failed assertion doesn't leave it behind
+#[cfg(test)]
+pub(crate) struct ScratchDir(PathBuf);
+
+#[cfg(test)]
+impl std::ops::Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        journal::remove_any(&self.0).ok();
+    }
+}
+
+/// Create a scratch directory under the system's temp dir, named for the
+/// test and unique to this process
+#[cfg(test)]
+pub(crate) fn scratch_dir(name: &str) -> ScratchDir {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "rip-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    journal::remove_any(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    ScratchDir(dir)
+}
@@ -1,53 +1,45 @@
 // -*- compile-command: "cargo build" -*-
 #[macro_use]
 extern crate clap;
-extern crate core;
 #[macro_use]
 extern crate error_chain;
-extern crate time;
+extern crate rm_improved;
 extern crate walkdir;
 
 use clap::{App, Arg};
-use std::io::{BufRead, BufReader, Read, Write};
-use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use errors::*;
+use rm_improved::errors;
+use rm_improved::{humanize_bytes, prompt_yes, tree_size, uninterrupted};
+use rm_improved::{BigFiles, BuryOptions, Damage, Grave, GraveFilter, Graveyard, StoreKind, Trash};
+use rm_improved::{Notice, Question};
+use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
-use std::{env, fs, io};
+use std::{env, fs};
 use walkdir::WalkDir;
-mod errors {
-    error_chain! {}
-}
-use errors::*;
-
-include!("util.rs");
 
-const GRAVEYARD: &str = "/tmp/graveyard";
-const RECORD: &str = ".record";
-const LINES_TO_INSPECT: usize = 6;
-const FILES_TO_INSPECT: usize = 6;
-const BIG_FILE_THRESHOLD: u64 = 500000000; // 500 MB
-
-struct RecordItem<'a> {
-    _time: &'a str,
-    orig: &'a Path,
-    dest: &'a Path,
-}
+mod config;
+use config::{Format, Settings};
 
 fn main() {
     if let Err(ref e) = run() {
-        let stderr = &mut ::std::io::stderr();
-        let errmsg = "Error writing to stderr";
+        print_error(e);
+        ::std::process::exit(e.exit_code());
+    }
+}
 
-        writeln!(stderr, "error: {}", e).expect(errmsg);
+/// Print an error and its chain of causes to stderr
+fn print_error(e: &Error) {
+    let stderr = &mut ::std::io::stderr();
+    let errmsg = "Error writing to stderr";
 
-        for e in e.iter().skip(1) {
-            writeln!(stderr, "caused by: {}", e).expect(errmsg);
-        }
+    writeln!(stderr, "error: {}", e).expect(errmsg);
 
-        if let Some(backtrace) = e.backtrace() {
-            writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
-        }
+    for e in e.iter().skip(1) {
+        writeln!(stderr, "caused by: {}", e).expect(errmsg);
+    }
 
-        ::std::process::exit(1);
+    if let Some(backtrace) = e.backtrace() {
+        writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
     }
 }
 
@@ -71,18 +63,103 @@ Send files to the graveyard (/tmp/graveyard-$USER by default) instead of unlinki
                 .long("graveyard")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("config")
+                .help("Read settings from this file instead of $XDG_CONFIG_HOME/rip/config.toml")
+                .long("config")
+                .value_name("file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .help("Print the settings in effect and where each one came from")
+                .long("print-config"),
+        )
         .arg(
             Arg::with_name("decompose")
                 .help("Permanently deletes (unlink) the entire graveyard")
                 .short("d")
                 .long("decompose"),
         )
+        .arg(
+            Arg::with_name("message")
+                .help(
+                    "Note why TARGET is being removed in the record.  With -s, -u or -d, only \
+                     use graves whose message contains this.",
+                )
+                .short("m")
+                .long("message")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tag")
+                .help(
+                    "Tag TARGET in the record (can be repeated).  With -s, -u or -d, only use \
+                     graves with this tag.",
+                )
+                .long("tag")
+                .value_name("name")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|t| {
+                    if t.is_empty() || t.contains(',') {
+                        Err(String::from("tags can't be empty or contain commas"))
+                    } else {
+                        Ok(())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("migrate-record")
+                .help("Move the graveyard's record into a text file or an SQLite database")
+                .long("migrate-record")
+                .value_name("store")
+                .takes_value(true)
+                .possible_values(&["text", "sqlite"]),
+        )
+        .arg(
+            Arg::with_name("migrate-graveyard")
+                .help(
+                    "Move the graveyard to dir, and rewrite entries in the record that still \
+                     point into the old one",
+                )
+                .long("migrate-graveyard")
+                .value_name("dir")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("seance")
                 .help("Prints files that were sent under the current directory")
                 .short("s")
                 .long("seance"),
         )
+        .arg(
+            Arg::with_name("all-graveyards")
+                .help(
+                    "Search and restore from every graveyard that --graveyard, $GRAVEYARD, \
+                     $XDG_DATA_HOME, the config file or the defaults could point to.  On its \
+                     own, list them.",
+                )
+                .long("all-graveyards"),
+        )
+        .arg(
+            Arg::with_name("history")
+                .help(
+                    "Prints graves that have been purged or decomposed, or just those whose \
+                     original path contains pattern",
+                )
+                .long("history")
+                .value_name("pattern")
+                .min_values(0)
+                .max_values(1),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("Output format for -s and --history")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["plain", "json"]),
+        )
         .arg(
             Arg::with_name("unbury")
                 .help(
@@ -94,6 +171,138 @@ Send files to the graveyard (/tmp/graveyard-$USER by default) instead of unlinki
                 .value_name("target")
                 .min_values(0),
         )
+        .arg(
+            Arg::with_name("import-trash")
+                .help(
+                    "Move everything in the desktop trash (~/.local/share/Trash) into the \
+                     graveyard.  Combine with -s to take only what was trashed from under the \
+                     current directory.",
+                )
+                .long("import-trash"),
+        )
+        .arg(
+            Arg::with_name("export-trash")
+                .help(
+                    "Move graves into the desktop trash, so that file managers can restore them: \
+                     the given file(s) in the graveyard, everything printed by -s with -s, or else \
+                     every grave",
+                )
+                .long("export-trash")
+                .value_name("target")
+                .min_values(0),
+        )
+        .arg(
+            Arg::with_name("atomic")
+                .help(
+                    "Bury all targets or none of them: check that every target exists and \
+                     isn't protected first, and return already buried targets if a later one \
+                     fails.  Refuses to unlink anything, since that can't be undone",
+                )
+                .long("atomic")
+                .conflicts_with("permanent"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .help(
+                    "Verify files copied across filesystems with BLAKE3 before removing the \
+                     source, and store checksums in the record",
+                )
+                .long("checksum"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .help("Check graves against the checksums stored in the record")
+                .long("verify"),
+        )
+        .arg(
+            Arg::with_name("fsck")
+                .help(
+                    "Check the record against the graveyard for entries whose graves are gone, \
+                     files it doesn't mention, malformed lines and duplicates",
+                )
+                .long("fsck"),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .help("With --fsck, fix what it finds")
+                .long("repair")
+                .requires("fsck"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Don't show progress while copying across filesystems")
+                .short("q")
+                .long("quiet"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .help("Copy up to N files at once when burying a directory across filesystems")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("permanent")
+                .help("Unlink TARGET instead of burying it, leaving a tombstone in the record")
+                .short("p")
+                .long("permanent"),
+        )
+        .arg(
+            Arg::with_name("big-files")
+                .help(
+                    "What to do with files over 500 MB (or big_file_threshold in the config) \
+                     that need to be copied to be buried [default: prompt]",
+                )
+                .long("big-files")
+                .takes_value(true)
+                .possible_values(&["prompt", "bury", "delete"]),
+        )
+        .arg(
+            Arg::with_name("shred")
+                .help(
+                    "Overwrite file contents before unlinking them, with --permanent, \
+                     --decompose or when unlinking from the graveyard",
+                )
+                .long("shred"),
+        )
+        .arg(
+            Arg::with_name("shred-passes")
+                .help("Number of times --shred overwrites each file [default: 3]")
+                .long("shred-passes")
+                .takes_value(true)
+                .value_name("N"),
+        )
+        .arg(
+            Arg::with_name("protect")
+                .help(
+                    "Refuse to remove this path, like / and $HOME (can be repeated, or set in \
+                     $RIP_PROTECT separated by colons)",
+                )
+                .long("protect")
+                .value_name("path")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("no-preserve-root")
+                .help("Allow removing /, $HOME, mount points and other protected paths")
+                .long("no-preserve-root"),
+        )
+        .arg(
+            Arg::with_name("one-file-system")
+                .help("When burying a directory, leave any filesystems mounted inside it in place")
+                .long("one-file-system"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .help(
+                    "Print the .riprc policy that applies to each target, or with -s, everything \
+                     recorded about each grave",
+                )
+                .short("v")
+                .long("verbose"),
+        )
         .arg(
             Arg::with_name("inspect")
                 .help("Prints some info about TARGET before prompting for action")
@@ -102,406 +311,555 @@ Send files to the graveyard (/tmp/graveyard-$USER by default) instead of unlinki
         )
         .get_matches();
 
-    let graveyard: &PathBuf = &{
-        if let Some(flag) = matches.value_of("graveyard") {
-            flag.to_owned()
-        } else if let Ok(env) = env::var("GRAVEYARD") {
-            env
-        } else if let Ok(mut env) = env::var("XDG_DATA_HOME") {
-            if !env.ends_with(std::path::MAIN_SEPARATOR) {
-                env.push(std::path::MAIN_SEPARATOR);
-            }
-            env.push_str("graveyard");
-            env
-        } else {
-            format!("{}-{}", GRAVEYARD, get_user())
-        }}.into();
+    let cwd: PathBuf = env::current_dir().chain_err(|| "Failed to get current dir")?;
+    let settings = Settings::load(&matches, &cwd)?;
+    rm_improved::set_owner(settings.owner);
+    if matches.is_present("print-config") {
+        settings.print();
+        return Ok(());
+    }
+    let graveyard =
+        &mut Graveyard::open_with(&settings.graveyard.value, settings.record_store.value)?;
 
-    if matches.is_present("decompose") {
-        if prompt_yes("Really unlink the entire graveyard?") {
-            fs::remove_dir_all(graveyard).chain_err(|| "Couldn't unlink graveyard")?;
+    if let Some(kind) = matches
+        .value_of("migrate-record")
+        .and_then(StoreKind::parse)
+    {
+        let from = graveyard.record().kind();
+        let moved = graveyard.migrate_record(kind)?;
+        if from == kind {
+            println!("The record is already kept as {}", kind.as_str());
+        } else {
+            println!(
+                "Moved {} entries from the {} record to {}",
+                moved,
+                from.as_str(),
+                graveyard.record().path().display()
+            );
         }
         return Ok(());
     }
 
-    let record: &Path = &graveyard.join(RECORD);
-    let cwd: PathBuf = env::current_dir().chain_err(|| "Failed to get current dir")?;
+    if let Some(dest) = matches.value_of("migrate-graveyard") {
+        let opts = &BuryOptions {
+            progress: !matches.is_present("quiet")
+                && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+            low_space_percent: settings.low_space_percent.value,
+            ask: Some(&prompt),
+            notify: Some(&report),
+            ..BuryOptions::default()
+        };
+        let from = graveyard.path().to_owned();
+        let rewritten = graveyard.migrate_graveyard(dest, opts)?;
+        println!(
+            "Moved the graveyard from {} to {}",
+            from.display(),
+            graveyard.path().display()
+        );
+        if rewritten > 0 {
+            println!(
+                "Rewrote {} entries that pointed into the old one",
+                rewritten
+            );
+        }
+        println!("Use it with --graveyard, $GRAVEYARD or graveyard in the config");
+        return Ok(());
+    }
+    let graveyard = &*graveyard;
 
-    if let Some(t) = matches.values_of("unbury") {
-        // Vector to hold the grave path of items we want to unbury.
-        // This will be used to determine which items to remove from the
-        // record following the unbury.
-        // Initialize it with the targets passed to -r
-        let graves_to_exhume: &mut Vec<PathBuf> = &mut t.map(PathBuf::from).collect();
+    let shred = if matches.is_present("shred") {
+        Some(settings.shred_passes.value)
+    } else {
+        None
+    };
+
+    let tags: &Vec<String> = &matches
+        .values_of("tag")
+        .map(|t| t.map(String::from).collect())
+        .unwrap_or_default();
+    let filter = &GraveFilter {
+        message: matches.value_of("message").map(String::from),
+        tags: tags.clone(),
+    };
+
+    let protected: &Vec<PathBuf> = &settings.protect.iter().map(|p| p.value.clone()).collect();
+    let opts = &BuryOptions {
+        checksum: matches.is_present("checksum"),
+        progress: !matches.is_present("quiet") && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+        jobs: settings.jobs.value,
+        permanent: matches.is_present("permanent"),
+        shred,
+        shred_passes: settings.shred_passes.value,
+        protected,
+        no_preserve_root: matches.is_present("no-preserve-root"),
+        one_file_system: matches.is_present("one-file-system"),
+        per_filesystem_graveyards: settings.per_filesystem_graveyards.value,
+        big_file_threshold: settings.big_file_threshold.value,
+        big_files: settings.big_files.value,
+        low_space_percent: settings.low_space_percent.value,
+        verbose: matches.is_present("verbose"),
+        reversible: matches.is_present("atomic"),
+        message: matches.value_of("message"),
+        tags,
+        ask: Some(&prompt),
+        notify: Some(&report),
+    };
+    if let Err(e) = graveyard.recover(opts) {
+        print_error(&e);
+    }
 
-        // If -s is also passed, push all files found by seance onto
-        // the graves_to_exhume.
-        if matches.is_present("seance") {
-            if let Ok(f) = fs::File::open(record) {
-                let gravepath = join_absolute(graveyard, cwd).to_string_lossy().into_owned();
-                for grave in seance(f, gravepath) {
-                    graves_to_exhume.push(grave);
-                }
+    if matches.is_present("decompose") {
+        if filter.is_empty() {
+            if prompt_yes("Really unlink the entire graveyard?") {
+                graveyard.decompose(opts)?;
+            }
+        } else {
+            let count = graveyard
+                .list()?
+                .iter()
+                .filter(|g| !g.is_tombstone() && filter.matches(g))
+                .count();
+            if count == 0 {
+                println!("No graves match");
+            } else if prompt_yes(format!("Really unlink {} matching grave(s)?", count)) {
+                graveyard.purge_matching(filter, opts)?;
             }
         }
+        return Ok(());
+    }
 
-        // Otherwise, add the last deleted file
-        if graves_to_exhume.is_empty() {
-            if let Ok(s) = get_last_bury(record) {
-                graves_to_exhume.push(s);
+    if matches.is_present("fsck") {
+        let repair = matches.is_present("repair");
+        let report = graveyard.fsck(repair)?;
+        for grave in &report.dangling {
+            println!(
+                "DANGLING  {} (from {})",
+                grave.dest.display(),
+                grave.orig.display()
+            );
+        }
+        for orphan in &report.orphans {
+            println!("ORPHAN    {}", orphan.display());
+        }
+        for (line, contents) in &report.malformed {
+            println!("MALFORMED line {}: {}", line, contents);
+        }
+        for grave in &report.duplicates {
+            println!("DUPLICATE {}", grave.dest.display());
+        }
+        println!(
+            "{} entries: {} dangling, {} orphaned, {} malformed, {} duplicate",
+            report.entries,
+            report.dangling.len(),
+            report.orphans.len(),
+            report.malformed.len(),
+            report.duplicates.len()
+        );
+        if report.problems() > 0 {
+            if !repair {
+                bail!(ErrorKind::RecordInconsistent(report.problems()));
             }
+            println!("Repaired {} problem(s)", report.problems());
         }
+        return Ok(());
+    }
 
-        // Go through the graveyard and exhume all the graves
-        let f = fs::File::open(record).chain_err(|| "Couldn't read the record")?;
-        for line in lines_of_graves(f, graves_to_exhume) {
-            let entry: RecordItem = record_entry(&line);
-            let orig: &Path = &{
-                if symlink_exists(entry.orig) {
-                    rename_grave(entry.orig)
-                } else {
-                    PathBuf::from(entry.orig)
-                }
-            };
-            bury(entry.dest, orig).chain_err(|| {
-                format!(
-                    "Unbury failed: couldn't copy files from {} to {}",
-                    entry.dest.display(),
-                    orig.display()
-                )
-            })?;
-            println!("Returned {} to {}", entry.dest.display(), orig.display());
+    if settings.retention_days.value > 0 {
+        match graveyard.purge_older_than(settings.retention_days.value, opts) {
+            Ok(0) => {}
+            Ok(n) => eprintln!(
+                "Decomposed {} grave(s) older than {} days",
+                n, settings.retention_days.value
+            ),
+            Err(e) => print_error(&e),
         }
+    }
 
-        // Reopen the record and then delete lines corresponding to exhumed graves
-        if let Err(e) = fs::File::open(record)
-            .and_then(|f| delete_lines_from_record(f, record, graves_to_exhume))
-        {
-            bail!("Failed to remove unburied files from record: {}", e);
+    // With --all-graveyards, every known graveyard that exists, starting
+    // with the one in use
+    let others: Vec<Graveyard> = if matches.is_present("all-graveyards") {
+        open_others(&settings, graveyard)
+    } else {
+        Vec::new()
+    };
+    let graveyards: Vec<&Graveyard> = std::iter::once(graveyard).chain(&others).collect();
+
+    if matches.is_present("import-trash") {
+        let trash = Trash::home().ok_or("Couldn't find the trash: $HOME isn't set")?;
+        for item in trash.items()? {
+            if matches.is_present("seance") && !item.orig.starts_with(&cwd) {
+                continue;
+            }
+            let grave = graveyard.import_trashed(&item, opts)?;
+            println!(
+                "Imported {} to {}",
+                item.file.display(),
+                grave.dest.display()
+            );
         }
         return Ok(());
     }
 
-    if matches.is_present("seance") {
-        let gravepath = join_absolute(graveyard, cwd);
-        let f = fs::File::open(record).chain_err(|| "Failed to read record")?;
-        for grave in seance(f, gravepath.to_string_lossy()) {
-            println!("{}", grave.display());
+    if let Some(t) = matches.values_of("export-trash") {
+        let trash = Trash::home().ok_or("Couldn't find the trash: $HOME isn't set")?;
+        let targets: Vec<PathBuf> = t.map(PathBuf::from).collect();
+        for g in &graveyards {
+            let graves = if matches.is_present("seance") {
+                g.seance(&cwd)?
+            } else {
+                g.list()?
+            };
+            for grave in graves {
+                if grave.is_tombstone()
+                    || !(targets.is_empty() || targets.contains(&grave.dest))
+                    || !filter.matches(&grave)
+                {
+                    continue;
+                }
+                let file = g.export_to_trash(&grave, &trash, opts)?;
+                println!("Exported {} to {}", grave.dest.display(), file.display());
+            }
         }
         return Ok(());
     }
 
-    if let Some(targets) = matches.values_of("TARGET") {
-        for target in targets {
-            // Check if source exists
-            if let Ok(metadata) = fs::symlink_metadata(target) {
-                // Canonicalize the path unless it's a symlink
-                let source = &if !metadata.file_type().is_symlink() {
-                    cwd.join(target)
-                        .canonicalize()
-                        .chain_err(|| "Failed to canonicalize path")?
-                } else {
-                    cwd.join(target)
-                };
-
-                if matches.is_present("inspect") {
-                    if metadata.is_dir() {
-                        // Get the size of the directory and all its contents
-                        println!(
-                            "{}: directory, {} including:",
-                            target,
-                            humanize_bytes(
-                                WalkDir::new(source)
-                                    .into_iter()
-                                    .filter_map(|x| x.ok())
-                                    .filter_map(|x| x.metadata().ok())
-                                    .map(|x| x.len())
-                                    .sum::<u64>()
-                            )
-                        );
-
-                        // Print the first few top-level files in the directory
-                        for entry in WalkDir::new(source)
-                            .min_depth(1)
-                            .max_depth(1)
-                            .into_iter()
-                            .filter_map(|entry| entry.ok())
-                            .take(FILES_TO_INSPECT)
-                        {
-                            println!("{}", entry.path().display());
-                        }
-                    } else {
-                        println!("{}: file, {}", target, humanize_bytes(metadata.len()));
-                        // Read the file and print the first few lines
-                        if let Ok(f) = fs::File::open(source) {
-                            for line in BufReader::new(f)
-                                .lines()
-                                .take(LINES_TO_INSPECT)
-                                .filter_map(|line| line.ok())
-                            {
-                                println!("> {}", line);
-                            }
-                        } else {
-                            println!("Error reading {}", source.display());
-                        }
-                    }
-                    if !prompt_yes(format!("Send {} to the graveyard?", target)) {
-                        continue;
-                    }
+    if let Some(t) = matches.values_of("unbury") {
+        // Graves we want to unbury and the graveyards they're in, starting
+        // with the targets passed to -u
+        let mut graves_to_exhume: Vec<(&Graveyard, Grave)> = Vec::new();
+        for dest in t {
+            let dest = cwd.join(dest);
+            for g in &graveyards {
+                if let Some(grave) = g.find(&dest)? {
+                    graves_to_exhume.push((g, grave));
+                    break;
                 }
+            }
+        }
 
-                // If rip is called on a file already in the graveyard, prompt
-                // to permanently delete it instead.
-                if source.starts_with(graveyard) {
-                    println!("{} is already in the graveyard.", source.display());
-                    if prompt_yes("Permanently unlink it?") {
-                        if fs::remove_dir_all(source).is_err() {
-                            fs::remove_file(source).chain_err(|| "Couldn't unlink")?;
-                        }
-                        continue;
-                    } else {
-                        println!("Skipping {}", source.display());
-                        return Ok(());
+        // If -s is also passed, push all files found by seance onto
+        // the graves_to_exhume.
+        if matches.is_present("seance") {
+            for g in &graveyards {
+                for grave in g.seance(&cwd)? {
+                    if !grave.is_tombstone()
+                        && !graves_to_exhume.iter().any(|(_, e)| e.dest == grave.dest)
+                    {
+                        graves_to_exhume.push((g, grave));
                     }
                 }
+            }
+        } else if graves_to_exhume.is_empty() && !filter.is_empty() {
+            // Or everything with the message or tags asked for
+            for g in &graveyards {
+                graves_to_exhume.extend(
+                    g.list()?
+                        .into_iter()
+                        .filter(|g| !g.is_tombstone())
+                        .map(|grave| (*g, grave)),
+                );
+            }
+        }
 
-                let dest: &Path = &{
-                    let dest = join_absolute(graveyard, source);
-                    // Resolve a name conflict if necessary
-                    if symlink_exists(&dest) {
-                        rename_grave(dest)
-                    } else {
-                        dest
+        // Otherwise, add the last deleted file
+        if graves_to_exhume.is_empty() && filter.is_empty() {
+            let mut last: Option<(&Graveyard, Grave)> = None;
+            for g in &graveyards {
+                if let Some(grave) = g.last()? {
+                    if last
+                        .as_ref()
+                        .is_none_or(|(_, l)| grave.recorded_at() > l.recorded_at())
+                    {
+                        last = Some((g, grave));
                     }
-                };
-
-                bury(source, dest)
-                    .or_else(|e| {
-                        fs::remove_dir_all(dest).ok();
-                        Err(e)
-                    })
-                    .chain_err(|| "Failed to bury file")?;
-                // Clean up any partial buries due to permission error
-                write_log(source, dest, record)
-                    .chain_err(|| format!("Failed to write record at {}", record.display()))?;
-            } else {
-                bail!("Cannot remove {}: no such file or directory", target);
+                }
             }
+            graves_to_exhume.extend(last);
         }
-    } else {
-        println!("{}\nrip -h for help", matches.usage());
-    }
 
-    Ok(())
-}
-
-/// Write deletion history to record
-fn write_log<S, D, R>(source: S, dest: D, record: R) -> io::Result<()>
-where
-    S: AsRef<Path>,
-    D: AsRef<Path>,
-    R: AsRef<Path>,
-{
-    let (source, dest) = (source.as_ref(), dest.as_ref());
-    let mut f = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(record)?;
-    writeln!(
-        f,
-        "{}\t{}\t{}",
-        time::now().ctime(),
-        source.display(),
-        dest.display()
-    )?;
-
-    Ok(())
-}
+        // Go through the graveyard and exhume all the graves.  Never offer
+        // to destroy big files on the way out.
+        let opts = &BuryOptions {
+            big_files: BigFiles::Bury,
+            ..*opts
+        };
+        for (g, grave) in graves_to_exhume {
+            if !filter.matches(&grave) {
+                continue;
+            }
+            let orig = g.unbury(&grave, opts)?;
+            println!("Returned {} to {}", grave.dest.display(), orig.display());
+        }
+        return Ok(());
+    }
 
-fn bury<S: AsRef<Path>, D: AsRef<Path>>(source: S, dest: D) -> Result<()> {
-    let (source, dest) = (source.as_ref(), dest.as_ref());
-    // Try a simple rename, which will only work within the same mount point.
-    // Trying to rename across filesystems will throw errno 18.
-    if fs::rename(source, dest).is_ok() {
+    if matches.is_present("verify") {
+        let result = graveyard.verify()?;
+        for (grave, damage) in &result.damaged {
+            match damage {
+                Damage::Modified => println!("MODIFIED {}", grave.display()),
+                Damage::Missing(e) => println!("MISSING  {} ({})", grave.display(), e),
+            }
+        }
+        println!(
+            "{} intact, {} damaged or missing, {} without a checksum",
+            result.intact,
+            result.damaged.len(),
+            result.unchecked
+        );
+        if !result.damaged.is_empty() {
+            bail!(ErrorKind::Damaged(result.damaged.len()));
+        }
         return Ok(());
     }
 
-    // If that didn't work, then copy and rm.
-    let parent = dest.parent().ok_or("Couldn't get parent of dest")?;
-    fs::create_dir_all(parent).chain_err(|| "Couldn't create parent dir")?;
+    if matches.is_present("history") {
+        let pattern = matches.value_of("history").unwrap_or_default();
+        let mut graves = Vec::new();
+        for g in &graveyards {
+            graves.extend(g.history().search(pattern)?);
+        }
+        for grave in graves {
+            if settings.format.value == Format::Json {
+                println!("{}", grave.to_json());
+            } else {
+                println!(
+                    "{} (removed {}, purged {} by {})",
+                    grave.orig.display(),
+                    grave.time,
+                    grave.field("purged_at").unwrap_or_default(),
+                    grave.field("purged").unwrap_or_default()
+                );
+            }
+        }
+        return Ok(());
+    }
 
-    if fs::symlink_metadata(source)
-        .chain_err(|| "Couldn't get metadata")?
-        .is_dir()
-    {
-        // Walk the source, creating directories and copying files as needed
-        for entry in WalkDir::new(source).into_iter().filter_map(|e| e.ok()) {
-            // Path without the top-level directory
-            let orphan: &Path = entry
-                .path()
-                .strip_prefix(source)
-                .chain_err(|| "Parent directory isn't a prefix of child directories?")?;
-            if entry.file_type().is_dir() {
-                fs::create_dir_all(dest.join(orphan)).chain_err(|| {
-                    format!(
-                        "Failed to create {} in {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
+    if matches.is_present("seance") {
+        let mut graves = Vec::new();
+        for g in &graveyards {
+            graves.extend(g.seance(&cwd)?);
+        }
+        for grave in graves {
+            if !filter.matches(&grave) {
+                continue;
+            }
+            if settings.format.value == Format::Json {
+                println!("{}", grave.to_json());
+            } else if grave.is_tombstone() {
+                println!(
+                    "{} (destroyed: {}, {})",
+                    grave.orig.display(),
+                    grave.field("tombstone").unwrap_or_default(),
+                    humanize_bytes(
+                        grave
+                            .field("size")
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(0)
                     )
-                })?;
+                );
             } else {
-                copy_file(entry.path(), dest.join(orphan)).chain_err(|| {
-                    format!(
-                        "Failed to copy file from {} to {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
-                    )
-                })?;
+                println!("{}", grave.dest.display());
+            }
+            if settings.format.value != Format::Plain {
+                continue;
+            }
+            if matches.is_present("verbose") {
+                print_details(&grave);
+            } else {
+                // Annotations are worth showing even without -v
+                for key in &["message", "tags"] {
+                    if let Some(value) = grave.field(key) {
+                        println!("    {:<10}{}", key, value);
+                    }
+                }
             }
         }
-        fs::remove_dir_all(source)
-            .chain_err(|| format!("Failed to remove dir: {}", source.display()))?;
-    } else {
-        copy_file(source, dest).chain_err(|| {
-            format!(
-                "Failed to copy file from {} to {}",
-                source.display(),
-                dest.display()
-            )
-        })?;
-        fs::remove_file(source)
-            .chain_err(|| format!("Failed to remove file: {}", source.display()))?;
+        return Ok(());
     }
 
-    Ok(())
-}
-
-fn copy_file<S: AsRef<Path>, D: AsRef<Path>>(source: S, dest: D) -> io::Result<()> {
-    let (source, dest) = (source.as_ref(), dest.as_ref());
-    let metadata = fs::symlink_metadata(source)?;
-    let filetype = metadata.file_type();
+    if let Some(targets) = matches.values_of("TARGET") {
+        let targets: Vec<&str> = targets.collect();
+        let atomic = matches.is_present("atomic");
+
+        // In atomic mode, make sure every target exists and may be removed
+        // before burying anything so that a typo or a protected path doesn't
+        // leave us with a partial removal.
+        if atomic {
+            for target in &targets {
+                graveyard.check(target, opts)?;
+            }
+        }
 
-    if metadata.len() > BIG_FILE_THRESHOLD {
-        println!(
-            "About to copy a big file ({} is {})",
-            source.display(),
-            humanize_bytes(metadata.len())
-        );
-        if prompt_yes("Permanently delete this file instead?") {
-            return Ok(());
+        // Everything buried so far, in case we need to roll back
+        let mut buried: Vec<Grave> = Vec::new();
+        let mut succeeded: usize = 0;
+        let mut failures: usize = 0;
+        // Exit status of the first failure
+        let mut status = 0;
+        for target in &targets {
+            if matches.is_present("inspect") && !inspect(target, &settings) {
+                continue;
+            }
+            match graveyard.bury(target, opts) {
+                Ok(graves) => {
+                    buried.extend(graves);
+                    succeeded += 1;
+                }
+                Err(e) if atomic => {
+                    let rolled_back = uninterrupted(|| rollback(graveyard, &buried, opts));
+                    return Err(e).chain_err(|| match rolled_back {
+                        Ok(()) => format!("Aborted, returned {} buried target(s)", succeeded),
+                        Err(r) => format!("Aborted, but rollback failed: {}", r),
+                    });
+                }
+                Err(e) => {
+                    print_error(&e);
+                    failures += 1;
+                    if status == 0 {
+                        status = e.exit_code();
+                    }
+                    if rm_improved::interrupted() {
+                        break;
+                    }
+                }
+            }
         }
-    }
 
-    if filetype.is_file() {
-        if let Err(e) = fs::copy(source, dest) {
-            // println!("Failed to copy {} to {}", source.display(), dest.display());
-            return Err(e);
+        if failures > 0 {
+            bail!(ErrorKind::PartlyFailed(
+                failures,
+                targets.len(),
+                succeeded,
+                status
+            ));
         }
-    } else if filetype.is_fifo() {
-        let mode = metadata.permissions().mode();
-        std::process::Command::new("mkfifo")
-            .arg(dest)
-            .arg("-m")
-            .arg(mode.to_string());
-    } else if filetype.is_symlink() {
-        let target = fs::read_link(source)?;
-        std::os::unix::fs::symlink(target, dest)?;
-    } else if let Err(e) = fs::copy(source, dest) {
-        // Special file: Try copying it as normal, but this probably won't work
-        println!("Non-regular file or directory: {}", source.display());
-        if !prompt_yes("Permanently delete the file?") {
-            return Err(e);
+    } else if matches.is_present("all-graveyards") {
+        for g in &graveyards {
+            let graves = g.list()?.iter().filter(|g| !g.is_tombstone()).count();
+            let source = settings
+                .known_graveyards
+                .iter()
+                .find(|k| k.value == g.path())
+                .map_or(String::new(), |k| k.source.to_string());
+            println!(
+                "{:<40} # {} grave(s), {}",
+                g.path().display(),
+                graves,
+                source
+            );
         }
-        // Create a dummy file to act as a marker in the graveyard
-        let mut marker = fs::File::create(dest)?;
-        marker.write_all(
-            b"This is a marker for a file that was \
-                           permanently deleted.  Requiescat in pace.",
-        )?;
+    } else {
+        println!("{}\nrip -h for help", matches.usage());
     }
 
     Ok(())
 }
 
-/// Return the path in the graveyard of the last file to be buried.
-/// As a side effect, any valid last files that are found in the record but
-/// not on the filesystem are removed from the record.
-fn get_last_bury<R: AsRef<Path>>(record: R) -> io::Result<PathBuf> {
-    let graves_to_exhume: &mut Vec<PathBuf> = &mut Vec::new();
-    let mut f = fs::File::open(record.as_ref())?;
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
-
-    // This could be cleaned up more if/when for loops can return a value
-    for entry in contents.lines().rev().map(record_entry) {
-        // Check that the file is still in the graveyard.
-        // If it is, return the corresponding line.
-        if symlink_exists(entry.dest) {
-            if !graves_to_exhume.is_empty() {
-                delete_lines_from_record(f, record, graves_to_exhume)?;
-            }
-            return Ok(PathBuf::from(entry.dest));
-        } else {
-            // File is gone, mark the grave to be removed from the record
-            graves_to_exhume.push(PathBuf::from(entry.dest));
+/// Open the known graveyards other than the one in use, without creating or
+/// changing anything, skipping those that don't exist or have no record and
+/// reporting those we refuse to use
+fn open_others(settings: &Settings, graveyard: &Graveyard) -> Vec<Graveyard> {
+    let mut others = Vec::new();
+    for known in &settings.known_graveyards {
+        if known.value == graveyard.path() {
+            continue;
+        }
+        match Graveyard::open_existing(&known.value, settings.record_store.value) {
+            Ok(g) => others.push(g),
+            Err(ref e) if matches!(*e.kind(), ErrorKind::NotFound(_)) => {}
+            Err(e) => print_error(&e),
         }
     }
+    others
+}
 
-    if !graves_to_exhume.is_empty() {
-        delete_lines_from_record(f, record, graves_to_exhume)?;
-    }
-    Err(io::Error::new(io::ErrorKind::NotFound, "But nobody came"))
+/// Ask the user whatever burying needs to know
+fn prompt(question: &Question) -> bool {
+    prompt_yes(question.to_string())
 }
 
-/// Parse a line in the record into a `RecordItem`
-fn record_entry(line: &str) -> RecordItem {
-    let mut tokens = line.split('\t');
-    let time: &str = tokens.next().expect("Bad format: column A");
-    let orig: &str = tokens.next().expect("Bad format: column B");
-    let dest: &str = tokens.next().expect("Bad format: column C");
-    RecordItem {
-        _time: time,
-        orig: Path::new(orig),
-        dest: Path::new(dest),
+/// Tell the user about something that happened while burying
+fn report(notice: &Notice) {
+    match notice {
+        _ if notice.is_warning() => eprintln!("warning: {}", notice),
+        Notice::Reverted(..) | Notice::Completed(..) => eprintln!("{}", notice),
+        _ => println!("{}", notice),
     }
 }
 
-/// Takes a vector of grave paths and returns the respective lines in the record
-fn lines_of_graves<'a>(f: fs::File, graves: &'a [PathBuf]) -> impl Iterator<Item = String> + 'a {
-    BufReader::new(f)
-        .lines()
-        .filter_map(|l| l.ok())
-        .filter(move |l| graves.into_iter().any(|y| y == record_entry(l).dest))
-}
+/// Print some info about target and ask whether to go ahead and bury it
+fn inspect(target: &str, settings: &Settings) -> bool {
+    let metadata = match fs::symlink_metadata(target) {
+        Ok(metadata) => metadata,
+        // Let burying it report the error
+        Err(_) => return true,
+    };
+    if metadata.is_dir() {
+        // Get the size of the directory and all its contents
+        println!(
+            "{}: directory, {} including:",
+            target,
+            humanize_bytes(tree_size(target).0)
+        );
 
-/// Returns an iterator over all graves in the record that are under gravepath
-fn seance<T: AsRef<str>>(f: fs::File, gravepath: T) -> impl Iterator<Item = PathBuf> {
-    BufReader::new(f)
-        .lines()
-        .filter_map(|l| l.ok())
-        .map(|l| PathBuf::from(record_entry(&l).dest))
-        .filter(move |d| d.starts_with(gravepath.as_ref()))
+        // Print the first few top-level files in the directory
+        for entry in WalkDir::new(target)
+            .min_depth(1)
+            .max_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .take(settings.files_to_inspect.value)
+        {
+            println!("{}", entry.path().display());
+        }
+    } else {
+        println!("{}: file, {}", target, humanize_bytes(metadata.len()));
+        // Read the file and print the first few lines
+        if let Ok(f) = fs::File::open(target) {
+            for line in BufReader::new(f)
+                .lines()
+                .take(settings.lines_to_inspect.value)
+                .map_while(|line| line.ok())
+            {
+                println!("> {}", line);
+            }
+        } else {
+            println!("Error reading {}", Path::new(target).display());
+        }
+    }
+    prompt_yes(format!("Send {} to the graveyard?", target))
 }
 
-/// Takes a vector of grave paths and removes the respective lines from the record
-fn delete_lines_from_record<R: AsRef<Path>>(
-    f: fs::File,
-    record: R,
-    graves: &[PathBuf],
-) -> io::Result<()> {
-    let record = record.as_ref();
-    // Get the lines to write back to the record, which is every line except
-    // the ones matching the exhumed graves.  Store them in a vector
-    // since we'll be overwriting the record in-place.
-    let lines_to_write: Vec<String> = BufReader::new(f)
-        .lines()
-        .filter_map(|l| l.ok())
-        .filter(|l| !graves.into_iter().any(|y| y == record_entry(l).dest))
-        .collect();
-    let mut f = fs::File::create(record)?;
-    for line in lines_to_write {
-        writeln!(f, "{}", line)?;
+/// Print everything the record says about a grave, indented under it
+fn print_details(grave: &Grave) {
+    println!("    {:<10}{}", "from", grave.orig.display());
+    println!("    {:<10}{}", "removed", grave.time);
+    for (key, value) in grave.pairs() {
+        if key == "size" {
+            println!(
+                "    {:<10}{}",
+                key,
+                humanize_bytes(value.parse().unwrap_or(0))
+            );
+        } else {
+            println!("    {:<10}{}", key, value);
+        }
     }
+}
 
+/// Return already buried targets to where they came from, undoing a
+/// partially completed atomic removal.
+fn rollback(graveyard: &Graveyard, buried: &[Grave], opts: &BuryOptions) -> Result<()> {
+    for grave in buried.iter().rev() {
+        let orig = graveyard.unbury(grave, opts).chain_err(|| {
+            format!(
+                "Couldn't return {} to {}",
+                grave.dest.display(),
+                grave.orig.display()
+            )
+        })?;
+        println!("Returned {} to {}", grave.dest.display(), orig.display());
+    }
     Ok(())
 }
@@ -0,0 +1,80 @@
+//! Finding out which filesystem a path lives on.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io, mem};
+use walkdir::{WalkDir, WalkDirIterator};
+
+/// Device ID of the filesystem holding path, or holding its nearest
+/// existing ancestor if path doesn't exist yet
+pub fn device<P: AsRef<Path>>(path: P) -> Option<u64> {
+    path.as_ref()
+        .ancestors()
+        .find_map(|p| fs::metadata(p).ok())
+        .map(|m| m.dev())
+}
+
+/// Top-level directory of the filesystem that path is on
+pub fn mount_root<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let dev = device(path)?;
+    let mut root = None;
+    for ancestor in path.ancestors() {
+        match fs::metadata(ancestor) {
+            Ok(ref m) if m.dev() == dev => root = Some(ancestor),
+            Ok(_) => break,
+            Err(_) => continue,
+        }
+    }
+    root.map(Path::to_path_buf)
+}
+
+/// Bytes available to unprivileged users and total size in bytes of the
+/// filesystem holding path
+pub fn space<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let frsize = stat.f_frsize as u64;
+    Ok((stat.f_bavail as u64 * frsize, stat.f_blocks as u64 * frsize))
+}
+
+/// True if path is the root of a filesystem
+pub fn is_mount_point<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    match (fs::symlink_metadata(path), path.parent()) {
+        (Ok(ref m), Some(parent)) if m.is_dir() => device(parent) != Some(m.dev()),
+        (Ok(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Mount points inside dir, without descending into them
+pub fn mount_points_under<P: AsRef<Path>>(dir: P) -> Vec<PathBuf> {
+    let dir = dir.as_ref();
+    let dev = match device(dir) {
+        Some(dev) => dev,
+        None => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    let mut it = WalkDir::new(dir).min_depth(1).into_iter();
+    while let Some(entry) = it.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        if entry.metadata().map(|m| m.dev() != dev).unwrap_or(false) {
+            found.push(entry.path().to_owned());
+            it.skip_current_dir();
+        }
+    }
+    found
+}
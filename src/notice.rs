@@ -0,0 +1,159 @@
+//! Questions and news for whoever is using the library.
+//!
+//! Burying runs into things only the user can decide, like whether to
+//! destroy a big file rather than copy it, and things they'll want to hear
+//! about, like a mount point being left behind.  The library never prompts
+//! or prints itself: it puts a [`Question`] to `BuryOptions::ask` and hands
+//! each [`Notice`] to `BuryOptions::notify`, and `rip` does the talking.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::humanize_bytes;
+
+/// Something to decide before going ahead.  With no one to ask, the answer
+/// is no.
+pub enum Question<'a> {
+    /// Unlink a target that's already in the graveyard
+    UnlinkGrave(&'a Path),
+    /// Bury everything in a target except the graveyard inside it
+    BuryAroundGraveyard(&'a Path),
+    /// Copy `bytes` of source even though it leaves only `left` free on fs
+    LowSpace {
+        source: &'a Path,
+        bytes: u64,
+        left: u64,
+        fs: &'a Path,
+    },
+    /// Delete a file over the big file threshold instead of copying it
+    DeleteBigFile(&'a Path, u64),
+    /// Delete a file that can't be copied
+    DeleteSpecialFile(&'a Path),
+}
+
+/// Something that happened along the way
+pub enum Notice {
+    /// The policy that applies to a target, if any, when `verbose` is set
+    Policy(PathBuf, Option<String>),
+    /// A target left alone because the user said not to unlink it
+    Skipped(PathBuf),
+    /// A mount point left where it is inside a buried directory
+    SkippedMountPoint(PathBuf),
+    /// A move an earlier run was interrupted in, which was undone
+    Reverted(PathBuf, PathBuf),
+    /// A move an earlier run was interrupted in, which was finished
+    Completed(PathBuf, PathBuf),
+    /// Shredding under a path may leave the data on disk, and why
+    UnreliableShred(PathBuf, String),
+    /// A per-filesystem graveyard that was passed over for the main one,
+    /// and why
+    UntrustedGraveyard(PathBuf, String),
+    /// A grave recorded without a checksum because it couldn't be read,
+    /// and why
+    NoChecksum(PathBuf, String),
+    /// A file that was unlinked without being overwritten, since its
+    /// contents live on under its other hard links
+    HardLinked(PathBuf),
+}
+
+impl Notice {
+    /// Whether this is something that may have gone wrong, rather than news
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self,
+            Notice::UnreliableShred(..)
+                | Notice::UntrustedGraveyard(..)
+                | Notice::NoChecksum(..)
+                | Notice::HardLinked(_)
+        )
+    }
+}
+
+impl fmt::Display for Question<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Question::UnlinkGrave(path) => write!(
+                f,
+                "{} is already in the graveyard.\nPermanently unlink it?",
+                path.display()
+            ),
+            Question::BuryAroundGraveyard(path) => write!(
+                f,
+                "{} contains the graveyard.\nBury everything in it except the graveyard?",
+                path.display()
+            ),
+            Question::LowSpace {
+                source,
+                bytes,
+                left,
+                fs,
+            } => write!(
+                f,
+                "Copying {} ({}) will leave only {} free on {}.  Continue?",
+                source.display(),
+                humanize_bytes(bytes),
+                humanize_bytes(left),
+                fs.display()
+            ),
+            Question::DeleteBigFile(path, size) => write!(
+                f,
+                "About to copy a big file ({} is {})\nPermanently delete this file instead?",
+                path.display(),
+                humanize_bytes(size)
+            ),
+            Question::DeleteSpecialFile(path) => write!(
+                f,
+                "Non-regular file or directory: {}\nPermanently delete the file?",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notice::Policy(path, Some(policy)) => write!(f, "{}: {}", path.display(), policy),
+            Notice::Policy(path, None) => write!(f, "{}: no policy", path.display()),
+            Notice::Skipped(path) => write!(f, "Skipping {}", path.display()),
+            Notice::SkippedMountPoint(path) => {
+                write!(f, "Skipping mount point {}", path.display())
+            }
+            Notice::Reverted(source, dest) => write!(
+                f,
+                "Reverted interrupted move of {} to {}",
+                source.display(),
+                dest.display()
+            ),
+            Notice::Completed(source, dest) => write!(
+                f,
+                "Completed interrupted move of {} to {}",
+                source.display(),
+                dest.display()
+            ),
+            Notice::UnreliableShred(path, reason) => write!(
+                f,
+                "{} is {}; overwriting it may leave the original data on disk",
+                path.display(),
+                reason
+            ),
+            Notice::UntrustedGraveyard(path, problem) => write!(
+                f,
+                "Not using graveyard {} ({}); using the main graveyard instead",
+                path.display(),
+                problem
+            ),
+            Notice::NoChecksum(path, problem) => write!(
+                f,
+                "Buried {}, but couldn't checksum it ({}); recording it without one",
+                path.display(),
+                problem
+            ),
+            Notice::HardLinked(path) => write!(
+                f,
+                "{} has other hard links; unlinking it without overwriting",
+                path.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Per-tree policies from `.riprc` files.
+//!
+//! A `.riprc` is TOML that applies to everything under the directory it's
+//! in.  For each target we use the nearest one found by walking up from the
+//! target's directory.  Patterns are globs relative to that directory, and
+//! a pattern that matches a directory applies to everything inside it.  A
+//! `.riprc` that someone other than us or root owns or can write to is
+//! refused rather than trusted.
+//!
+//! ```toml
+//! graveyard = ".graveyard"  # relative to the .riprc
+//! protect = ["*.key"]       # refuse to remove these
+//! permanent = ["*.o"]       # unlink these instead of burying them
+//! shred = ["secrets"]       # overwrite and unlink these
+//! ```
+
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+use crate::graveyard::owner;
+
+pub const RIPRC: &str = ".riprc";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RiprcFile {
+    graveyard: Option<String>,
+    protect: Vec<String>,
+    permanent: Vec<String>,
+    shred: Vec<String>,
+}
+
+pub struct Policy {
+    /// The `.riprc` this was read from
+    pub path: PathBuf,
+    dir: PathBuf,
+    /// Graveyard for everything under this tree
+    pub graveyard: Option<PathBuf>,
+    protect: Vec<Pattern>,
+    permanent: Vec<Pattern>,
+    shred: Vec<Pattern>,
+}
+
+/// What a policy says to do with one target
+pub struct Decision<'a> {
+    pub policy: &'a Policy,
+    /// The pattern protecting the target, if any
+    pub protected_by: Option<&'a str>,
+    pub permanent: bool,
+    pub shred: bool,
+}
+
+impl Policy {
+    /// Find the policy for path, if any `.riprc` applies to it
+    pub fn find(path: &Path) -> Result<Option<Policy>> {
+        for dir in path.ancestors().skip(1) {
+            let riprc = dir.join(RIPRC);
+            if riprc.is_file() {
+                check_trusted(&riprc)?;
+                return Policy::read(&riprc).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(riprc: &Path) -> Result<Policy> {
+        let contents = std::fs::read_to_string(riprc)
+            .chain_err(|| format!("Couldn't read {}", riprc.display()))?;
+        let file: RiprcFile =
+            toml::from_str(&contents).chain_err(|| format!("Invalid {}", riprc.display()))?;
+        let dir = riprc.parent().unwrap_or(riprc).to_owned();
+        let patterns = |globs: &[String]| -> Result<Vec<Pattern>> {
+            globs
+                .iter()
+                .map(|g| {
+                    Pattern::new(g)
+                        .chain_err(|| format!("Invalid pattern {:?} in {}", g, riprc.display()))
+                })
+                .collect()
+        };
+        Ok(Policy {
+            path: riprc.to_owned(),
+            graveyard: file.graveyard.map(|g| dir.join(g)),
+            protect: patterns(&file.protect)?,
+            permanent: patterns(&file.permanent)?,
+            shred: patterns(&file.shred)?,
+            dir,
+        })
+    }
+
+    /// Apply the policy to path, which should be under the policy's tree
+    pub fn decide(&self, path: &Path) -> Decision<'_> {
+        let relative = path.strip_prefix(&self.dir).unwrap_or(path);
+        let matching = |patterns: &'_ [Pattern]| -> Option<usize> {
+            let options = MatchOptions {
+                require_literal_separator: true,
+                ..MatchOptions::new()
+            };
+            patterns.iter().position(|p| {
+                relative
+                    .ancestors()
+                    .filter(|a| !a.as_os_str().is_empty())
+                    .any(|a| p.matches_path_with(a, options))
+            })
+        };
+        Decision {
+            policy: self,
+            protected_by: matching(&self.protect).map(|i| self.protect[i].as_str()),
+            permanent: matching(&self.permanent).is_some(),
+            shred: matching(&self.shred).is_some(),
+        }
+    }
+}
+
+/// Refuse a `.riprc` that anyone but us or root could have written, since it
+/// can send our files to its graveyard or have them shredded
+fn check_trusted(riprc: &Path) -> Result<()> {
+    let metadata =
+        std::fs::metadata(riprc).chain_err(|| format!("Couldn't check {}", riprc.display()))?;
+    let uid = owner().uid;
+    let problem = if metadata.uid() != uid && metadata.uid() != 0 {
+        format!("it's owned by uid {}, not {} or root", metadata.uid(), uid)
+    } else if metadata.mode() & 0o022 != 0 {
+        format!(
+            "other users can write to it (mode {:o})",
+            metadata.mode() & 0o7777
+        )
+    } else {
+        return Ok(());
+    };
+    bail!(ErrorKind::UntrustedPolicy(riprc.to_owned(), problem))
+}
+
+impl fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "policy {}", self.policy.path.display())?;
+        let mut rules = Vec::new();
+        if let Some(ref g) = self.policy.graveyard {
+            rules.push(format!("graveyard {}", g.display()));
+        }
+        if let Some(pattern) = self.protected_by {
+            rules.push(format!("protected by {:?}", pattern));
+        }
+        if self.permanent {
+            rules.push(String::from("permanent"));
+        }
+        if self.shred {
+            rules.push(String::from("shred"));
+        }
+        if !rules.is_empty() {
+            write!(f, " ({})", rules.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Progress display for slow copies across filesystems.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::humanize_bytes;
+
+/// How often to redraw the progress line
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Tracks bytes and files copied so far, occasionally redrawing a status
+/// line on stderr.  Counters are atomic so copies can report from any thread.
+pub struct Progress {
+    enabled: bool,
+    total_bytes: u64,
+    total_files: u64,
+    bytes: AtomicU64,
+    files: AtomicU64,
+    start: Instant,
+    last_draw: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    pub fn new(total_bytes: u64, total_files: u64) -> Progress {
+        Progress {
+            enabled: true,
+            total_bytes,
+            total_files,
+            bytes: AtomicU64::new(0),
+            files: AtomicU64::new(0),
+            start: Instant::now(),
+            last_draw: Mutex::new(None),
+        }
+    }
+
+    /// A progress tracker that never draws anything
+    pub fn hidden() -> Progress {
+        let mut progress = Progress::new(0, 0);
+        progress.enabled = false;
+        progress
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn add_bytes(&self, n: u64) {
+        self.bytes.fetch_add(n, Ordering::Relaxed);
+        self.draw();
+    }
+
+    pub fn file_done(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.draw();
+    }
+
+    /// Erase the progress line, e.g. before prompting
+    pub fn clear(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[K");
+            *self.last_draw.lock().unwrap() = None;
+        }
+    }
+
+    fn draw(&self) {
+        if !self.enabled {
+            return;
+        }
+        let mut last_draw = self.last_draw.lock().unwrap();
+        let now = Instant::now();
+        if last_draw.is_some_and(|t| now.duration_since(t) < REDRAW_INTERVAL) {
+            return;
+        }
+        *last_draw = Some(now);
+
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let files = self.files.load(Ordering::Relaxed);
+        let elapsed = now.duration_since(self.start).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (bytes as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let eta = self
+            .total_bytes
+            .saturating_sub(bytes)
+            .checked_div(rate)
+            .map_or_else(|| String::from("?"), format_duration);
+        let stderr = &mut ::std::io::stderr();
+        write!(
+            stderr,
+            "\r\x1b[K{} of {}, {}/{} files, {}/s, ETA {}",
+            humanize_bytes(bytes),
+            humanize_bytes(self.total_bytes),
+            files,
+            self.total_files,
+            humanize_bytes(rate),
+            eta
+        )
+        .and_then(|_| stderr.flush())
+        .ok();
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.last_draw.get_mut().map(|t| t.is_some()).unwrap_or(false) {
+            self.clear();
+        }
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,605 @@
+//! The record: a log of everything in the graveyard.
+//!
+//! Each entry holds `time`, `orig` and `dest`, followed by optional
+//! `key=value` fields.  Files that were destroyed rather than buried leave a
+//! tombstone, whose dest is `TOMBSTONE`.  Graves in the graveyard are kept
+//! by their path relative to it, so that the graveyard can be moved; older
+//! records, and graves in other graveyards, have absolute paths.  The record is kept by a
+//! `RecordStore`: either a text file with one tab-separated entry per line,
+//! or an SQLite database.
+
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use serde::Deserialize;
+
+use crate::errors::*;
+use crate::{chown_to_owner, create_dir_all_owned, hostname, real_uid, symlink_exists};
+
+pub const RECORD: &str = ".record";
+pub const RECORD_DB: &str = ".record.sqlite";
+
+/// Stand-in for the grave of a file that was destroyed instead of buried
+pub const TOMBSTONE: &str = "(destroyed)";
+
+/// How `time` is written in the record
+const TIME_FORMAT: &str = "%a %b %e %T %Y";
+
+/// An entry in the record: something that was buried, or destroyed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grave {
+    /// Local time it was recorded, as written by ctime(3)
+    pub time: String,
+    /// Where it was removed from
+    pub orig: PathBuf,
+    /// Where it is now, or `TOMBSTONE`
+    pub dest: PathBuf,
+    /// Optional `key=value` columns following the dest
+    pub fields: Vec<String>,
+}
+
+impl Grave {
+    /// A new entry saying that orig was just moved to dest, along with who
+    /// did it
+    pub fn new<O, D>(orig: O, dest: D, fields: &[String]) -> Grave
+    where
+        O: AsRef<Path>,
+        D: AsRef<Path>,
+    {
+        let mut fields = fields.to_vec();
+        fields.push(format!("euid={}", unsafe { libc::geteuid() }));
+        fields.push(format!("ruid={}", real_uid()));
+        if let Some(host) = hostname() {
+            fields.push(format_field("host", &host));
+        }
+        fields.push(format!("pid={}", std::process::id()));
+        if let Ok(cwd) = env::current_dir() {
+            fields.push(format_field("cwd", &cwd.to_string_lossy()));
+        }
+        let cmd: Vec<String> = env::args_os()
+            .map(|a| quote_arg(&a.to_string_lossy()))
+            .collect();
+        fields.push(format_field("cmd", &cmd.join(" ")));
+        Grave {
+            time: time::now().ctime().to_string(),
+            orig: orig.as_ref().to_owned(),
+            dest: dest.as_ref().to_owned(),
+            fields,
+        }
+    }
+
+    /// Parse a line in a text record, or return None if it's malformed
+    pub fn parse(line: &str) -> Option<Grave> {
+        let mut tokens = line.split('\t');
+        let time = tokens.next()?;
+        let orig = tokens.next()?;
+        let dest = tokens.next()?;
+        Some(Grave {
+            time: time.to_owned(),
+            orig: PathBuf::from(&*unescape(orig)),
+            dest: PathBuf::from(&*unescape(dest)),
+            fields: tokens.map(str::to_owned).collect(),
+        })
+    }
+
+    /// Write as a line in a text record, without the newline
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{}\t{}\t{}",
+            self.time,
+            escape(&self.orig.to_string_lossy()),
+            escape(&self.dest.to_string_lossy())
+        );
+        for field in &self.fields {
+            line.push('\t');
+            line.push_str(field);
+        }
+        line
+    }
+
+    /// The tags it was buried with
+    pub fn tags(&self) -> Vec<String> {
+        match self.field("tags") {
+            Some(tags) => tags.split(',').map(str::to_owned).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The entry as kept in the record of the graveyard at root, with dest
+    /// relative to root if the grave is in there
+    pub fn relative_to(&self, root: &Path) -> Cow<'_, Grave> {
+        match self.dest.strip_prefix(root) {
+            Ok(dest) if !self.is_tombstone() => Cow::Owned(Grave {
+                dest: dest.to_owned(),
+                ..self.clone()
+            }),
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// The entry read from the record of the graveyard at root, with a
+    /// relative dest made absolute again
+    pub fn resolve(mut self, root: &Path) -> Grave {
+        if self.dest.is_relative() && !self.is_tombstone() {
+            self.dest = root.join(&self.dest);
+        }
+        self
+    }
+
+    /// True if this records a file that was destroyed rather than buried
+    pub fn is_tombstone(&self) -> bool {
+        self.field("tombstone").is_some()
+    }
+
+    /// Look up the value of an optional field
+    pub fn field(&self, key: &str) -> Option<Cow<'_, str>> {
+        self.pairs().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// The optional fields as keys and values, in order
+    pub fn pairs(&self) -> impl Iterator<Item = (&str, Cow<'_, str>)> {
+        self.fields
+            .iter()
+            .filter_map(|f| f.split_once('='))
+            .map(|(k, v)| (k, unescape(v)))
+    }
+
+    /// Seconds since the epoch when this was recorded, reading the local
+    /// time in the record as UTC
+    pub fn recorded_at(&self) -> Option<i64> {
+        time::strptime(&self.time, TIME_FORMAT)
+            .ok()
+            .map(|tm| tm.to_timespec().sec)
+    }
+
+    /// Render as a JSON object, with the optional fields in a nested object
+    pub fn to_json(&self) -> serde_json::Value {
+        let fields: serde_json::Map<String, serde_json::Value> = self
+            .pairs()
+            .map(|(k, v)| (k.to_owned(), v.into()))
+            .collect();
+        serde_json::json!({
+            "time": self.time,
+            "orig": self.orig.to_string_lossy(),
+            "dest": self.dest.to_string_lossy(),
+            "fields": fields,
+        })
+    }
+}
+
+/// Picks out entries by the message and tags they were buried with
+#[derive(Clone, Debug, Default)]
+pub struct GraveFilter {
+    /// Text the message has to contain
+    pub message: Option<String>,
+    /// Tags an entry has to have, all of them
+    pub tags: Vec<String>,
+}
+
+impl GraveFilter {
+    /// True if this lets every entry through
+    pub fn is_empty(&self) -> bool {
+        self.message.is_none() && self.tags.is_empty()
+    }
+
+    pub fn matches(&self, grave: &Grave) -> bool {
+        if let Some(ref message) = self.message {
+            if !grave
+                .field("message")
+                .is_some_and(|m| m.contains(message.as_str()))
+            {
+                return false;
+            }
+        }
+        let tags = grave.tags();
+        self.tags.iter().all(|t| tags.contains(t))
+    }
+}
+
+/// Write a `key=value` field, escaping the value so that it can't break the
+/// line it's on
+pub fn format_field(key: &str, value: &str) -> String {
+    format!("{}={}", key, escape(value))
+}
+
+/// Escape the characters that would break a line in a text record.  Paths
+/// in the record are escaped too.
+fn escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '\t', '\n', '\r']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn unescape(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            // Not something we escaped, such as a backslash in a path
+            // recorded before paths were escaped
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+/// Quote a command line argument for a shell, if it needs it
+fn quote_arg(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// The current time as `Grave::recorded_at` would read it back
+pub fn now() -> i64 {
+    time::strptime(&time::now().ctime().to_string(), TIME_FORMAT)
+        .map(|tm| tm.to_timespec().sec)
+        .unwrap_or(0)
+}
+
+/// Line numbers and contents of entries that couldn't be parsed
+pub type Malformed = Vec<(usize, String)>;
+
+/// How a graveyard's record is kept
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// `.record`, one line per entry
+    Text,
+    /// `.record.sqlite`, indexed by orig and dest
+    Sqlite,
+}
+
+impl StoreKind {
+    pub fn parse(s: &str) -> Option<StoreKind> {
+        match s {
+            "text" => Some(StoreKind::Text),
+            "sqlite" => Some(StoreKind::Sqlite),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StoreKind::Text => "text",
+            StoreKind::Sqlite => "sqlite",
+        }
+    }
+
+    /// Name of the record in the graveyard
+    pub fn file_name(self) -> &'static str {
+        match self {
+            StoreKind::Text => RECORD,
+            StoreKind::Sqlite => RECORD_DB,
+        }
+    }
+
+    /// The kind of record already in graveyard, preferring this one if
+    /// there are both, or this one if there's neither
+    pub fn existing<P: AsRef<Path>>(self, graveyard: P) -> StoreKind {
+        let other = match self {
+            StoreKind::Text => StoreKind::Sqlite,
+            StoreKind::Sqlite => StoreKind::Text,
+        };
+        let graveyard = graveyard.as_ref();
+        if !symlink_exists(graveyard.join(self.file_name()))
+            && symlink_exists(graveyard.join(other.file_name()))
+        {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Somewhere to keep the record
+pub trait RecordStore {
+    fn kind(&self) -> StoreKind;
+
+    fn path(&self) -> &Path;
+
+    /// Every entry in the record, oldest first.  A record that doesn't
+    /// exist yet is empty.
+    fn graves(&self) -> Result<Vec<Grave>>;
+
+    /// Like `graves`, but skipping malformed entries instead of failing,
+    /// and returning their line numbers and contents as well
+    fn scan(&self) -> Result<(Vec<Grave>, Malformed)> {
+        Ok((self.graves()?, Vec::new()))
+    }
+
+    /// Everything removed from under dir, including tombstones
+    fn seance(&self, dir: &Path) -> Result<Vec<Grave>> {
+        let mut graves = self.graves()?;
+        graves.retain(|g| g.orig.starts_with(dir));
+        Ok(graves)
+    }
+
+    /// The latest grave at dest, if the record has one.  Tombstones have no
+    /// grave, so they're never found.
+    fn find(&self, dest: &Path) -> Result<Option<Grave>> {
+        Ok(self
+            .graves()?
+            .into_iter()
+            .rev()
+            .find(|g| !g.is_tombstone() && g.dest == dest))
+    }
+
+    /// Add entries to the end of the record
+    fn append(&self, graves: &[Grave]) -> Result<()>;
+
+    /// Remove the entries for the given graves
+    fn remove(&self, graves: &[PathBuf]) -> Result<()>;
+
+    /// Replace every entry in the record with graves, all at once
+    fn replace(&self, graves: &[Grave]) -> Result<()>;
+
+    /// Return the last thing to be buried that's still in the graveyard, if
+    /// anything.  As a side effect, any later graves that are found in the
+    /// record but not on the filesystem are removed from the record.
+    fn last_bury(&self) -> Result<Option<Grave>> {
+        let mut graves_to_exhume: Vec<PathBuf> = Vec::new();
+        let mut last = None;
+        for grave in self.graves()?.into_iter().rev() {
+            if grave.is_tombstone() {
+                continue;
+            }
+            // Check that the file is still in the graveyard
+            if symlink_exists(&grave.dest) {
+                last = Some(grave);
+                break;
+            }
+            // File is gone, mark the grave to be removed from the record
+            graves_to_exhume.push(grave.dest);
+        }
+
+        if !graves_to_exhume.is_empty() {
+            self.remove(&graves_to_exhume)
+                .chain_err(|| "Failed to remove missing graves from record")?;
+        }
+        Ok(last)
+    }
+}
+
+/// Open the record of the given kind in graveyard
+pub fn open_store<P: AsRef<Path>>(graveyard: P, kind: StoreKind) -> Result<Box<dyn RecordStore>> {
+    let path = graveyard.as_ref().join(kind.file_name());
+    match kind {
+        StoreKind::Text => Ok(Box::new(TextRecord::new(path))),
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Ok(Box::new(crate::sqlite::SqliteRecord::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => bail!("This rip was built without SQLite support"),
+    }
+}
+
+/// The record as a text file, one entry per line
+pub struct TextRecord {
+    path: PathBuf,
+}
+
+impl TextRecord {
+    pub fn new<P: AsRef<Path>>(path: P) -> TextRecord {
+        TextRecord {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    fn write_failed(&self) -> String {
+        format!("Failed to write record at {}", self.path.display())
+    }
+
+    /// The graveyard the record is in
+    fn root(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("/"))
+    }
+
+    fn rewrite(&self, graves: &[PathBuf]) -> io::Result<()> {
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        // Get the lines to write back to the record, which is every line except
+        // the ones matching the exhumed graves.  Malformed lines are kept,
+        // and an unreadable one stops us before anything is lost.
+        let mut lines_to_write: Vec<String> =
+            BufReader::new(f).lines().collect::<io::Result<_>>()?;
+        lines_to_write.retain(|l| {
+            Grave::parse(l).is_none_or(|g| !graves.contains(&g.resolve(self.root()).dest))
+        });
+        self.write_lines(lines_to_write)
+    }
+
+    /// Write a new record alongside and rename it over the old one, so that
+    /// we never leave a half-written record behind
+    fn write_lines<I: IntoIterator<Item = String>>(&self, lines: I) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut f = fs::File::create(&tmp)?;
+        for line in lines {
+            writeln!(f, "{}", line)?;
+        }
+        f.sync_all()?;
+        chown_to_owner(&tmp)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl RecordStore for TextRecord {
+    fn kind(&self) -> StoreKind {
+        StoreKind::Text
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn graves(&self) -> Result<Vec<Grave>> {
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Failed to read record at {}", self.path.display()))
+            }
+        };
+        let mut graves = Vec::new();
+        for (i, line) in BufReader::new(f).lines().enumerate() {
+            let corrupt = || ErrorKind::RecordCorrupt(self.path.clone(), i + 1);
+            let line = line.chain_err(corrupt)?;
+            graves.push(
+                Grave::parse(&line)
+                    .ok_or_else(corrupt)?
+                    .resolve(self.root()),
+            );
+        }
+        Ok(graves)
+    }
+
+    fn scan(&self) -> Result<(Vec<Grave>, Malformed)> {
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok((Vec::new(), Vec::new()))
+            }
+            Err(e) => {
+                return Err(e)
+                    .chain_err(|| format!("Failed to read record at {}", self.path.display()))
+            }
+        };
+        let (mut graves, mut malformed) = (Vec::new(), Vec::new());
+        for (i, line) in BufReader::new(f).split(b'\n').enumerate() {
+            let line =
+                line.chain_err(|| format!("Failed to read record at {}", self.path.display()))?;
+            let line = String::from_utf8_lossy(&line);
+            match Grave::parse(&line) {
+                Some(grave) => graves.push(grave.resolve(self.root())),
+                None => malformed.push((i + 1, line.into_owned())),
+            }
+        }
+        Ok((graves, malformed))
+    }
+
+    fn append(&self, graves: &[Grave]) -> Result<()> {
+        // Tombstones can be written before anything has been buried
+        if let Some(parent) = self.path.parent() {
+            create_dir_all_owned(parent, 0o700).chain_err(|| self.write_failed())?;
+        }
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .chain_err(|| self.write_failed())?;
+        for grave in graves {
+            writeln!(f, "{}", grave.relative_to(self.root()).to_line())
+                .chain_err(|| self.write_failed())?;
+        }
+        chown_to_owner(&self.path).chain_err(|| self.write_failed())
+    }
+
+    fn remove(&self, graves: &[PathBuf]) -> Result<()> {
+        self.rewrite(graves).chain_err(|| self.write_failed())
+    }
+
+    fn replace(&self, graves: &[Grave]) -> Result<()> {
+        self.write_lines(graves.iter().map(|g| g.relative_to(self.root()).to_line()))
+            .chain_err(|| self.write_failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trips() {
+        for value in &[
+            "plain",
+            "tab\there",
+            "new\nline\r",
+            "back\\slash",
+            "\\t",
+            "\\",
+        ] {
+            assert_eq!(unescape(&escape(value)), *value);
+        }
+        assert_eq!(escape("a\tb\\c"), "a\\tb\\\\c");
+        assert!(matches!(escape("plain"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn unescape_keeps_unknown_escapes() {
+        // Paths recorded before they were escaped can have backslashes
+        assert_eq!(unescape("C:\\dir"), "C:\\dir");
+        assert_eq!(unescape("end\\"), "end\\");
+        assert_eq!(unescape("a\\\\d"), "a\\d");
+    }
+
+    #[test]
+    fn line_round_trips() {
+        let grave = Grave {
+            time: String::from("Sun Oct 18 22:00:04 2026"),
+            orig: PathBuf::from("/tmp/odd\tname\nhere\\"),
+            dest: PathBuf::from("/tmp/graveyard/tmp/odd\tname\nhere\\"),
+            fields: vec![
+                String::from("type=file"),
+                format_field("message", "two\nlines\tand\\more"),
+            ],
+        };
+        let line = grave.to_line();
+        assert!(!line.contains('\n'));
+        assert_eq!(line.split('\t').count(), 5);
+        let parsed = Grave::parse(&line).unwrap();
+        assert!(parsed == grave);
+        assert_eq!(parsed.field("message").unwrap(), "two\nlines\tand\\more");
+        assert!(Grave::parse("just\ttwo").is_none());
+    }
+
+    #[test]
+    fn dests_in_the_graveyard_are_kept_relative() {
+        let root = Path::new("/g");
+        let grave = Grave::new("/w/a", "/g/w/a", &[]);
+        let kept = grave.relative_to(root);
+        assert_eq!(kept.dest, Path::new("w/a"));
+        assert!(kept.into_owned().resolve(root) == grave);
+
+        // Legacy records have absolute dests, and a tombstone's isn't a path
+        let legacy = Grave::new("/w/b", "/elsewhere/w/b", &[]);
+        assert!(matches!(legacy.relative_to(root), Cow::Borrowed(_)));
+        assert_eq!(legacy.clone().resolve(root).dest, legacy.dest);
+        let tombstone = Grave::new("/w/c", TOMBSTONE, &["tombstone=permanent".to_owned()]);
+        assert!(matches!(tombstone.relative_to(root), Cow::Borrowed(_)));
+        assert_eq!(tombstone.resolve(root).dest, Path::new(TOMBSTONE));
+    }
+}
@@ -0,0 +1,164 @@
+//! Overwriting file contents before unlinking them.
+//!
+//! This is best effort: on copy-on-write or log-structured filesystems an
+//! overwrite goes to new blocks and leaves the old ones alone, and SSDs remap
+//! writes internally, so we warn whenever we can tell that's the case.
+
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::{fs, io};
+use walkdir::WalkDir;
+
+use crate::bury::BuryOptions;
+use crate::journal::remove_any;
+use crate::notice::Notice;
+
+/// Filesystems we've already warned about, by device ID
+static WARNED: Mutex<Option<HashSet<u64>>> = Mutex::new(None);
+
+/// Overwrite every regular file under path with `passes` rounds of random
+/// data, then unlink the whole tree.  Symlinks are unlinked without touching
+/// what they point to, and files with other hard links are left intact, since
+/// their contents live on under the other names.
+pub fn shred<P: AsRef<Path>>(path: P, passes: u32, opts: &BuryOptions) -> io::Result<()> {
+    let path = path.as_ref();
+    // walkdir follows a symlink it's given as the root
+    let filetype = fs::symlink_metadata(path)?.file_type();
+    if !filetype.is_file() && !filetype.is_dir() {
+        return remove_any(path);
+    }
+    warn_if_unreliable(path, opts);
+    for entry in WalkDir::new(path) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if entry.metadata()?.nlink() > 1 {
+            opts.tell(Notice::HardLinked(entry.path().to_owned()));
+            continue;
+        }
+        overwrite(entry.path(), passes)?;
+    }
+    remove_any(path)
+}
+
+/// Overwrite the contents of a single file in place
+fn overwrite(path: &Path, passes: u32) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new().write(true).open(path)?;
+    let len = f.metadata()?.len();
+    let mut seed = [0; 32];
+    fs::File::open("/dev/urandom")?.read_exact(&mut seed)?;
+    let mut stream = blake3::Hasher::new().update(&seed).finalize_xof();
+    let mut buf = vec![0; 1 << 16];
+    for _ in 0..passes {
+        f.seek(SeekFrom::Start(0))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len() as u64) as usize;
+            stream.fill(&mut buf[..n]);
+            f.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        f.sync_data()?;
+    }
+    f.set_len(0)?;
+    f.sync_all()
+}
+
+/// Warn, once per filesystem, if overwriting files under path probably
+/// won't destroy the data
+fn warn_if_unreliable(path: &Path, opts: &BuryOptions) {
+    let dev = match fs::symlink_metadata(path) {
+        Ok(m) => m.dev(),
+        Err(_) => return,
+    };
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    if !warned.get_or_insert_with(HashSet::new).insert(dev) {
+        return;
+    }
+    if let Some(reason) = unreliable_reason(path, dev) {
+        opts.tell(Notice::UnreliableShred(path.to_owned(), reason));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unreliable_reason(path: &Path, dev: u64) -> Option<String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(cpath.as_ptr(), &mut stat) } == 0 {
+        let kind = match stat.f_type as i64 {
+            0x9123_683e => Some("on btrfs, a copy-on-write filesystem"),
+            0x2fc1_2fc1 => Some("on ZFS, a copy-on-write filesystem"),
+            0xca45_1a4e => Some("on bcachefs, a copy-on-write filesystem"),
+            0xf2f5_2010 => Some("on F2FS, a log-structured filesystem"),
+            0x3434 => Some("on NILFS, a log-structured filesystem"),
+            0x0102_1994 => Some("in memory and may have been swapped out"),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            return Some(kind.to_owned());
+        }
+    }
+
+    // Solid state drives remap writes internally.  The block device for a
+    // partition keeps its queue settings in the parent device's directory.
+    let (major, minor) = (libc::major(dev), libc::minor(dev));
+    let sys = format!("/sys/dev/block/{}:{}", major, minor);
+    ["queue/rotational", "../queue/rotational"]
+        .iter()
+        .find_map(|f| fs::read_to_string(Path::new(&sys).join(f)).ok())
+        .filter(|rotational| rotational.trim() == "0")
+        .map(|_| String::from("on a solid state drive"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unreliable_reason(_path: &Path, _dev: u64) -> Option<String> {
+    Some(String::from(
+        "on a filesystem or drive that may not overwrite in place",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch_dir;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn symlink_root_is_unlinked_not_followed() {
+        let dir = scratch_dir("shred-symlink");
+        let keep = dir.join("keep.txt");
+        fs::write(&keep, "keep").unwrap();
+        let link = dir.join("link");
+        symlink(&keep, &link).unwrap();
+
+        shred(&link, 1, &BuryOptions::default()).unwrap();
+        assert!(fs::symlink_metadata(&link).is_err());
+        assert_eq!(fs::read_to_string(&keep).unwrap(), "keep");
+    }
+
+    #[test]
+    fn hard_linked_file_is_unlinked_not_overwritten() {
+        let dir = scratch_dir("shred-hardlink");
+        let keep = dir.join("keep.txt");
+        fs::write(&keep, "keep").unwrap();
+        let tree = dir.join("tree");
+        fs::create_dir(&tree).unwrap();
+        fs::hard_link(&keep, tree.join("link")).unwrap();
+        fs::write(tree.join("other"), "other").unwrap();
+
+        shred(&tree, 1, &BuryOptions::default()).unwrap();
+        assert!(fs::symlink_metadata(&tree).is_err());
+        assert_eq!(fs::read_to_string(&keep).unwrap(), "keep");
+
+        fs::hard_link(&keep, dir.join("root")).unwrap();
+        shred(dir.join("root"), 1, &BuryOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(&keep).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,290 @@
+//! The record as an SQLite database, for graveyards with more graves than a
+//! text file can comfortably hold.  Lookups by orig and dest are indexed, so
+//! a seance or an unbury doesn't have to read every entry.
+
+use rusqlite::{params, Connection, Row};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+use crate::record::{Grave, RecordStore, StoreKind};
+use crate::{chown_to_owner, create_dir_all_owned, symlink_exists};
+
+/// Paths are stored as bytes, since they needn't be UTF-8, and dests in the
+/// graveyard relative to it, as in a text record.  Optional fields are
+/// stored tab-separated.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS graves (
+        id INTEGER PRIMARY KEY,
+        time TEXT NOT NULL,
+        orig BLOB NOT NULL,
+        dest BLOB NOT NULL,
+        fields TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS graves_orig ON graves (orig);
+    CREATE INDEX IF NOT EXISTS graves_dest ON graves (dest);
+";
+
+const COLUMNS: &str = "SELECT time, orig, dest, fields FROM graves";
+const INSERT: &str = "INSERT INTO graves (time, orig, dest, fields) VALUES (?1, ?2, ?3, ?4)";
+
+pub struct SqliteRecord {
+    path: PathBuf,
+    db: Connection,
+}
+
+impl SqliteRecord {
+    /// Open the database at path, creating it if need be
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteRecord> {
+        let path = path.as_ref();
+        let failed = || format!("Couldn't open record at {}", path.display());
+        if let Some(parent) = path.parent() {
+            create_dir_all_owned(parent, 0o700).chain_err(failed)?;
+        }
+        let db = Connection::open(path).chain_err(failed)?;
+        db.execute_batch(SCHEMA).chain_err(failed)?;
+        chown_to_owner(path).chain_err(failed)?;
+        Ok(SqliteRecord {
+            path: path.to_owned(),
+            db,
+        })
+    }
+
+    /// The graveyard the record is in
+    fn root(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("/"))
+    }
+
+    fn query<P: rusqlite::Params>(&self, sql: &str, params: P) -> Result<Vec<Grave>> {
+        let read = || -> rusqlite::Result<Vec<Grave>> {
+            let mut stmt = self.db.prepare(sql)?;
+            let graves = stmt
+                .query_map(params, |row| grave(row, self.root()))?
+                .collect();
+            graves
+        };
+        read().chain_err(|| format!("Failed to read record at {}", self.path.display()))
+    }
+
+    fn write_failed(&self) -> String {
+        format!("Failed to write record at {}", self.path.display())
+    }
+}
+
+impl RecordStore for SqliteRecord {
+    fn kind(&self) -> StoreKind {
+        StoreKind::Sqlite
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn graves(&self) -> Result<Vec<Grave>> {
+        self.query(&format!("{} ORDER BY id", COLUMNS), [])
+    }
+
+    fn seance(&self, dir: &Path) -> Result<Vec<Grave>> {
+        // Everything under dir sorts between "dir/" and "dir0", since '0'
+        // follows '/'
+        let dir = bytes(dir).strip_suffix(b"/").unwrap_or(bytes(dir));
+        let (mut lo, mut hi) = (dir.to_vec(), dir.to_vec());
+        lo.push(b'/');
+        hi.push(b'0');
+        self.query(
+            &format!(
+                "{} WHERE orig = ?1 OR (orig >= ?2 AND orig < ?3) ORDER BY id",
+                COLUMNS
+            ),
+            params![dir, lo, hi],
+        )
+    }
+
+    fn find(&self, dest: &Path) -> Result<Option<Grave>> {
+        // Older entries have an absolute dest
+        let relative = dest.strip_prefix(self.root()).unwrap_or(dest);
+        let graves = self.query(
+            &format!("{} WHERE dest = ?1 OR dest = ?2 ORDER BY id DESC", COLUMNS),
+            [bytes(relative), bytes(dest)],
+        )?;
+        Ok(graves.into_iter().find(|g| !g.is_tombstone()))
+    }
+
+    fn append(&self, graves: &[Grave]) -> Result<()> {
+        let write = || -> rusqlite::Result<()> {
+            let tx = self.db.unchecked_transaction()?;
+            insert(&tx, graves, self.root())?;
+            tx.commit()
+        };
+        write().chain_err(|| self.write_failed())
+    }
+
+    fn remove(&self, graves: &[PathBuf]) -> Result<()> {
+        let write = || -> rusqlite::Result<()> {
+            let tx = self.db.unchecked_transaction()?;
+            {
+                // Older entries have an absolute dest
+                let mut stmt = tx.prepare("DELETE FROM graves WHERE dest = ?1 OR dest = ?2")?;
+                for dest in graves {
+                    let relative = dest.strip_prefix(self.root()).unwrap_or(dest);
+                    stmt.execute([bytes(relative), bytes(dest)])?;
+                }
+            }
+            tx.commit()
+        };
+        write().chain_err(|| self.write_failed())
+    }
+
+    fn replace(&self, graves: &[Grave]) -> Result<()> {
+        let write = || -> rusqlite::Result<()> {
+            let tx = self.db.unchecked_transaction()?;
+            tx.execute("DELETE FROM graves", [])?;
+            insert(&tx, graves, self.root())?;
+            tx.commit()
+        };
+        write().chain_err(|| self.write_failed())
+    }
+
+    fn last_bury(&self) -> Result<Option<Grave>> {
+        // Walk back from the newest entry rather than reading them all
+        let mut graves_to_exhume: Vec<PathBuf> = Vec::new();
+        let mut last = None;
+        let mut walk = || -> rusqlite::Result<()> {
+            let mut stmt = self.db.prepare(&format!("{} ORDER BY id DESC", COLUMNS))?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let grave = grave(row, self.root())?;
+                if grave.is_tombstone() {
+                    continue;
+                }
+                if symlink_exists(&grave.dest) {
+                    last = Some(grave);
+                    break;
+                }
+                graves_to_exhume.push(grave.dest);
+            }
+            Ok(())
+        };
+        walk().chain_err(|| format!("Failed to read record at {}", self.path.display()))?;
+
+        if !graves_to_exhume.is_empty() {
+            self.remove(&graves_to_exhume)
+                .chain_err(|| "Failed to remove missing graves from record")?;
+        }
+        Ok(last)
+    }
+}
+
+fn insert(db: &Connection, graves: &[Grave], root: &Path) -> rusqlite::Result<()> {
+    let mut stmt = db.prepare(INSERT)?;
+    for g in graves {
+        let g = g.relative_to(root);
+        stmt.execute(params![
+            g.time,
+            bytes(&g.orig),
+            bytes(&g.dest),
+            g.fields.join("\t")
+        ])?;
+    }
+    Ok(())
+}
+
+fn grave(row: &Row, root: &Path) -> rusqlite::Result<Grave> {
+    let fields: String = row.get(3)?;
+    let grave = Grave {
+        time: row.get(0)?,
+        orig: path(row.get(1)?),
+        dest: path(row.get(2)?),
+        fields: fields
+            .split('\t')
+            .filter(|f| !f.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    };
+    Ok(grave.resolve(root))
+}
+
+fn bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+fn path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::TOMBSTONE;
+    use crate::scratch_dir;
+
+    #[test]
+    fn seance_takes_only_what_was_under_dir() {
+        let graveyard = scratch_dir("sqlite-seance");
+        let record = SqliteRecord::open(graveyard.join(".record.sqlite")).unwrap();
+        let origs = [
+            "/w/dir",
+            "/w/dir.foo",
+            "/w/dir/x",
+            "/w/dir0",
+            "/w/di",
+            "/w/dir/sub/y",
+        ];
+        let graves: Vec<Grave> = origs
+            .iter()
+            .map(|o| Grave::new(o, graveyard.join(&o[1..]), &[]))
+            .collect();
+        record.append(&graves).unwrap();
+
+        for dir in &["/w/dir", "/w/dir/"] {
+            let found: Vec<PathBuf> = record
+                .seance(Path::new(dir))
+                .unwrap()
+                .into_iter()
+                .map(|g| g.orig)
+                .collect();
+            assert_eq!(
+                found,
+                vec![
+                    Path::new("/w/dir"),
+                    Path::new("/w/dir/x"),
+                    Path::new("/w/dir/sub/y")
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn find_skips_tombstones_and_reads_absolute_dests() {
+        let graveyard = scratch_dir("sqlite-find");
+        let record = SqliteRecord::open(graveyard.join(".record.sqlite")).unwrap();
+        let dest = graveyard.join("w/a");
+        record
+            .append(&[Grave::new("/w/a", &dest, &["n=1".to_owned()])])
+            .unwrap();
+        record
+            .append(&[Grave::new(
+                "/w/b",
+                TOMBSTONE,
+                &["tombstone=permanent".to_owned()],
+            )])
+            .unwrap();
+        assert_eq!(
+            record.find(&dest).unwrap().unwrap().field("n").unwrap(),
+            "1"
+        );
+        assert!(record.find(Path::new(TOMBSTONE)).unwrap().is_none());
+        assert!(record.find(&graveyard.join(TOMBSTONE)).unwrap().is_none());
+
+        // Entries from before dests were kept relative
+        record
+            .db
+            .execute(
+                "UPDATE graves SET dest = ?1 WHERE orig = ?2",
+                [bytes(&dest), b"/w/a"],
+            )
+            .unwrap();
+        assert_eq!(record.find(&dest).unwrap().unwrap().orig, Path::new("/w/a"));
+    }
+}
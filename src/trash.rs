@@ -0,0 +1,344 @@
+//! The freedesktop.org trash that desktop file managers use.
+//!
+//! A trash directory holds each trashed file in `files/`, and beside it in
+//! `info/` a `<name>.trashinfo` saying where it came from and when:
+//!
+//! ```text
+//! [Trash Info]
+//! Path=/home/jack/notes%20old.txt
+//! DeletionDate=2026-10-18T22:00:04
+//! ```
+//!
+//! Paths are percent-encoded, and the date is local time.  Graves can be
+//! moved into the trash and trashed files adopted into the graveyard, so
+//! that either can restore them.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use crate::bury::{bury, BigFiles, BuryOptions};
+use crate::errors::*;
+use crate::graveyard::{annotations, describe, Graveyard};
+use crate::record::Grave;
+use crate::{create_dir_all_owned, join_absolute, rename_grave, symlink_exists, tree_size};
+
+const INFO_SUFFIX: &str = ".trashinfo";
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+pub struct Trash {
+    path: PathBuf,
+}
+
+/// Something in the trash, as its `.trashinfo` describes it
+pub struct Trashed {
+    /// The trashed file in `files/`
+    pub file: PathBuf,
+    /// Its `.trashinfo` in `info/`
+    pub info: PathBuf,
+    /// Where it was trashed from
+    pub orig: PathBuf,
+    /// When it was trashed, in local time, if the info says
+    pub deleted: Option<time::Tm>,
+}
+
+impl Trash {
+    pub fn new<P: AsRef<Path>>(path: P) -> Trash {
+        Trash {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// The home trash, in `$XDG_DATA_HOME/Trash` or `~/.local/share/Trash`
+    pub fn home() -> Option<Trash> {
+        let data = match env::var_os("XDG_DATA_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&env::var_os("HOME")?).join(".local/share"),
+        };
+        Some(Trash::new(data.join("Trash")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Everything in the trash, skipping info files that can't be read or
+    /// whose file is missing
+    pub fn items(&self) -> Result<Vec<Trashed>> {
+        let info_dir = self.path.join("info");
+        let entries = match fs::read_dir(&info_dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).chain_err(|| format!("Couldn't read {}", info_dir.display())),
+        };
+        let mut items = Vec::new();
+        for entry in entries {
+            let info = entry
+                .chain_err(|| format!("Couldn't read {}", info_dir.display()))?
+                .path();
+            let name = match info
+                .file_name()
+                .and_then(|n| n.as_bytes().strip_suffix(INFO_SUFFIX.as_bytes()))
+            {
+                Some(name) => OsStr::from_bytes(name).to_owned(),
+                None => continue,
+            };
+            let file = self.path.join("files").join(&name);
+            if !symlink_exists(&file) {
+                continue;
+            }
+            if let Some(item) = self.parse_info(&info, file) {
+                items.push(item);
+            }
+        }
+        items.sort_by(|a, b| a.info.cmp(&b.info));
+        Ok(items)
+    }
+
+    fn parse_info(&self, info: &Path, file: PathBuf) -> Option<Trashed> {
+        let f = fs::File::open(info).ok()?;
+        let (mut orig, mut deleted) = (None, None);
+        for line in BufReader::new(f).lines().map_while(|l| l.ok()) {
+            if let Some(path) = line.strip_prefix("Path=") {
+                orig = Some(PathBuf::from(OsString::from_vec(decode(path))));
+            } else if let Some(date) = line.strip_prefix("DeletionDate=") {
+                deleted = time::strptime(date, DATE_FORMAT).ok();
+            }
+        }
+        // Only the trash in a filesystem's top directory has relative paths
+        let orig = self.path.parent()?.join(orig?);
+        Some(Trashed {
+            file,
+            info: info.to_owned(),
+            orig,
+            deleted,
+        })
+    }
+
+    /// Claim a name in the trash for orig by creating its info file, as
+    /// the spec asks, returning where the file itself should go
+    fn reserve(&self, orig: &Path, deleted: &str) -> Result<(PathBuf, PathBuf)> {
+        let failed = || format!("Couldn't write to the trash in {}", self.path.display());
+        for dir in &["files", "info"] {
+            create_dir_all_owned(&self.path.join(dir), 0o700).chain_err(failed)?;
+        }
+        let base = orig.file_name().unwrap_or_else(|| OsStr::new("unnamed"));
+        for i in 1_u64.. {
+            let mut name = base.to_owned();
+            if i > 1 {
+                name.push(format!(".{}", i));
+            }
+            let file = self.path.join("files").join(&name);
+            let mut info_name = name;
+            info_name.push(INFO_SUFFIX);
+            let info = self.path.join("info").join(info_name);
+            if symlink_exists(&file) {
+                continue;
+            }
+            let mut f = match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&info)
+            {
+                Ok(f) => f,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).chain_err(failed),
+            };
+            write!(
+                f,
+                "[Trash Info]\nPath={}\nDeletionDate={}\n",
+                encode(orig.as_os_str().as_bytes()),
+                deleted
+            )
+            .chain_err(failed)?;
+            return Ok((file, info));
+        }
+        unreachable!()
+    }
+}
+
+impl Graveyard {
+    /// Move something from the trash into the graveyard, recording it as
+    /// removed from where and when the trash says
+    pub fn import_trashed(&self, item: &Trashed, opts: &BuryOptions) -> Result<Grave> {
+        let metadata = fs::symlink_metadata(&item.file)
+            .chain_err(|| format!("Couldn't read {}", item.file.display()))?;
+        let dest = {
+            let dest = join_absolute(self.path(), &item.orig);
+            if symlink_exists(&dest) {
+                rename_grave(dest)
+            } else {
+                dest
+            }
+        };
+        if let Some(parent) = dest.parent() {
+            create_dir_all_owned(parent, 0o777).chain_err(|| "Couldn't create parent dir")?;
+        }
+        let opts = &BuryOptions {
+            permanent: false,
+            shred: None,
+            big_files: BigFiles::Bury,
+            ..*opts
+        };
+        bury(&item.file, &dest, opts, self.journal()).chain_err(|| {
+            format!(
+                "Couldn't move {} to {}",
+                item.file.display(),
+                dest.display()
+            )
+        })?;
+
+        let size = if metadata.is_dir() {
+            tree_size(&dest).0
+        } else {
+            metadata.len()
+        };
+        let mut fields = describe(&metadata, size);
+        fields.push(String::from("trash=imported"));
+        fields.extend(annotations(opts));
+        let mut grave = Grave::new(&item.orig, &dest, &fields);
+        if let Some(deleted) = item.deleted {
+            grave.time = time::at_utc(deleted.to_timespec()).asctime().to_string();
+        }
+        self.record().append(std::slice::from_ref(&grave))?;
+        self.journal().finish(&dest).ok();
+        fs::remove_file(&item.info)
+            .chain_err(|| format!("Couldn't remove {}", item.info.display()))?;
+        Ok(grave)
+    }
+
+    /// Move a grave into the trash and out of the record, returning where
+    /// it went
+    pub fn export_to_trash(
+        &self,
+        grave: &Grave,
+        trash: &Trash,
+        opts: &BuryOptions,
+    ) -> Result<PathBuf> {
+        // The record's local time, as the trash wants it
+        let deleted = grave
+            .recorded_at()
+            .map(|sec| time::at_utc(time::Timespec::new(sec, 0)))
+            .unwrap_or_else(time::now);
+        let deleted = time::strftime(DATE_FORMAT, &deleted).chain_err(|| "Bad deletion date")?;
+        let (file, info) = trash.reserve(&grave.orig, &deleted)?;
+        let opts = &BuryOptions {
+            permanent: false,
+            shred: None,
+            big_files: BigFiles::Bury,
+            ..*opts
+        };
+        if let Err(e) = bury(&grave.dest, &file, opts, self.journal()) {
+            fs::remove_file(&info).ok();
+            return Err(e).chain_err(|| {
+                format!(
+                    "Couldn't move {} to {}",
+                    grave.dest.display(),
+                    file.display()
+                )
+            });
+        }
+        self.record()
+            .remove(std::slice::from_ref(&grave.dest))
+            .chain_err(|| "Failed to remove exported files from record")?;
+        self.journal().finish(&file).ok();
+        Ok(file)
+    }
+}
+
+/// Percent-encode everything in a path but unreserved characters and `/`
+fn encode(path: &[u8]) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &b in path {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn decode(path: &str) -> Vec<u8> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| {
+            std::str::from_utf8(h)
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        });
+        match hex {
+            Some(b) if bytes[i] == b'%' => {
+                decoded.push(b);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch_dir;
+
+    #[test]
+    fn encode_round_trips() {
+        let paths: &[&[u8]] = &[
+            b"/home/jack/notes old.txt",
+            b"/tmp/100%/a%20b",
+            b"/tmp/tab\there",
+            b"/tmp/\xff\xfe",
+        ];
+        for &path in paths {
+            assert_eq!(decode(&encode(path)), path);
+        }
+        assert_eq!(encode(b"/a b%c"), "/a%20b%25c");
+    }
+
+    #[test]
+    fn decode_keeps_bad_escapes() {
+        assert_eq!(decode("/a%zz"), b"/a%zz");
+        assert_eq!(decode("/a%4"), b"/a%4");
+        assert_eq!(decode("/a%"), b"/a%");
+        assert_eq!(decode("/a%41%2f"), b"/aA/");
+    }
+
+    #[test]
+    fn parse_info_reads_path_and_date() {
+        let root = scratch_dir("trash-info");
+        let trash = Trash::new(root.join("Trash"));
+        fs::create_dir_all(trash.path().join("info")).unwrap();
+        let info = trash.path().join("info/a.trashinfo");
+
+        fs::write(
+            &info,
+            "[Trash Info]\nPath=/home/jack/100%25%20done\nDeletionDate=2026-10-18T22:00:04\n",
+        )
+        .unwrap();
+        let item = trash.parse_info(&info, PathBuf::from("a")).unwrap();
+        assert_eq!(item.orig, Path::new("/home/jack/100% done"));
+        let deleted = item.deleted.unwrap();
+        assert_eq!(
+            (deleted.tm_hour, deleted.tm_min, deleted.tm_sec),
+            (22, 0, 4)
+        );
+
+        // Paths in a trash at the top of a filesystem are relative to it
+        fs::write(&info, "[Trash Info]\nPath=docs/a\n").unwrap();
+        let item = trash.parse_info(&info, PathBuf::from("a")).unwrap();
+        assert_eq!(item.orig, root.join("docs/a"));
+        assert!(item.deleted.is_none());
+
+        fs::write(&info, "[Trash Info]\nDeletionDate=2026-10-18T22:00:04\n").unwrap();
+        assert!(trash.parse_info(&info, PathBuf::from("a")).is_none());
+    }
+}
@@ -1,35 +1,86 @@
 /// Concatenate two paths, even if the right argument is an absolute path.
 fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathBuf {
     let (left, right) = (left.as_ref(), right.as_ref());
-    left.join(
-        if let Ok(stripped) = right.strip_prefix("/") {
-            stripped
-        } else {
-            right
-        }
-    )
+    left.join(if let Ok(stripped) = right.strip_prefix("/") {
+        stripped
+    } else {
+        right
+    })
 }
 
-fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
+pub fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
     fs::symlink_metadata(path).is_ok()
 }
 
-fn get_user() -> String {
+pub fn get_user() -> String {
     env::var("USER").unwrap_or_else(|_| String::from("unknown"))
 }
 
+/// A user and group that graveyards belong to
+#[derive(Clone, Copy, PartialEq)]
+pub struct Owner {
+    uid: u32,
+    gid: u32,
+}
+
+impl Owner {
+    pub fn current() -> Owner {
+        unsafe {
+            Owner {
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+            }
+        }
+    }
+}
+
+/// The user who ran `sudo rip`, and their name, if that's how we're running
+pub fn sudo_user() -> Option<(Owner, String)> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+    let uid: u32 = env::var("SUDO_UID").ok()?.parse().ok()?;
+    let gid: u32 = env::var("SUDO_GID")
+        .ok()
+        .and_then(|g| g.parse().ok())
+        .unwrap_or(uid);
+    let name = env::var("SUDO_USER").ok()?;
+    Some((Owner { uid, gid }, name))
+}
+
+/// The uid of the person running rip, seeing through sudo
+fn real_uid() -> u32 {
+    sudo_user().map_or_else(|| unsafe { libc::getuid() }, |(owner, _)| owner.uid)
+}
+
+/// The name of this machine
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// Held while prompting so that prompts from copy threads don't interleave
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
+
 /// Prompt for user input, returning True if the first character is 'y' or 'Y'
-fn prompt_yes<T: AsRef<str>>(prompt: T) -> bool {
+pub fn prompt_yes<T: AsRef<str>>(prompt: T) -> bool {
+    let _lock = PROMPT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     print!("{} (y/N) ", prompt.as_ref());
     if io::stdout().flush().is_err() {
         // If stdout wasn't flushed properly, fallback to println
         println!("{} (y/N)", prompt.as_ref());
     }
     let stdin = BufReader::new(io::stdin());
-    stdin.bytes().next()
+    stdin
+        .bytes()
+        .next()
         .and_then(|c| c.ok())
         .map(|c| c as char)
-        .map(|c| (c == 'y' || c == 'Y'))
+        .map(|c| c == 'y' || c == 'Y')
         .unwrap_or(false)
 }
 
@@ -43,14 +94,15 @@ fn rename_grave<G: AsRef<Path>>(grave: G) -> PathBuf {
         .expect("Failed to rename duplicate file or directory")
 }
 
-fn humanize_bytes(bytes: u64) -> String {
+pub fn humanize_bytes(bytes: u64) -> String {
     let values = ["bytes", "KB", "MB", "GB", "TB"];
-    let pair = values.iter()
+    let pair = values
+        .iter()
         .enumerate()
-        .take_while(|x| bytes as usize / (1000 as usize).pow(x.0 as u32) > 10)
+        .take_while(|x| bytes as usize / 1000_usize.pow(x.0 as u32) > 10)
         .last();
     if let Some((i, unit)) = pair {
-        format!("{} {}", bytes as usize / (1000 as usize).pow(i as u32), unit)
+        format!("{} {}", bytes as usize / 1000_usize.pow(i as u32), unit)
     } else {
         format!("{} {}", bytes, values[0])
     }